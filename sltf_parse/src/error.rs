@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+use super::loc::{Loc, Span};
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum ParseErrorKind {
+    // Input that isn't whitespace and doesn't start any kind of token,
+    // e.g. a control character.
+    UnrecognizedInput,
+    // A malformed number literal (e.g. `0b102`), or one that doesn't fit
+    // in a Prim::Int.
+    InvalidNumber,
+    // A character literal that doesn't hold exactly one character.
+    InvalidCharacter,
+    // A bad escape in a string or character literal, e.g. `\q`.
+    InvalidEscape,
+    // A token that isn't allowed where it appeared, e.g. a stray `;`.
+    UnexpectedToken,
+    // A `:` that isn't followed by the name of the word being defined.
+    MissingWordName,
+    // A word definition with no closing `;`.
+    UnterminatedDefinition,
+    // A string literal with no closing `"`.
+    UnterminatedString,
+    // A control structure, e.g. an `if` with no closing `then`.
+    UnterminatedBlock,
+    // A `(` comment with no closing `)`.
+    UnterminatedComment,
+}
+
+impl ParseErrorKind {
+    // Whether this error just means the input stopped too early, so that
+    // more input (e.g. the next line in the repl) could fix it.
+    pub fn is_incomplete(self) -> bool {
+        matches!(self,
+                 ParseErrorKind::UnterminatedDefinition |
+                 ParseErrorKind::UnterminatedString |
+                 ParseErrorKind::UnterminatedBlock |
+                 ParseErrorKind::UnterminatedComment)
+    }
+}
+
+// A problem found while lexing or parsing. Besides the span, we keep the
+// file, the (1-based) line and column and a copy of the offending line, so
+// that the error can be displayed without having the source around.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub file: Option<Rc<str>>,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+    pub source_line: String,
+}
+
+impl ParseError {
+
+    pub fn new(kind: ParseErrorKind, message: String, loc: &Loc, source: &str) -> Self {
+        let start = loc.span.start;
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        ParseError {
+            kind,
+            message,
+            file: loc.file.clone(),
+            span: loc.span,
+            line: loc.line,
+            column: loc.column,
+            source_line: source[line_start..line_end].to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The caret underlines the span, but never past the end of the line
+        // (a span can run on, e.g. for a multi-line string).
+        let remaining = self.source_line.chars().count() + 1 - self.column;
+        let width = (self.span.end - self.span.start).min(remaining).max(1);
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file)?;
+        }
+        writeln!(f, "line {}, column {}: {}", self.line, self.column, self.message)?;
+        writeln!(f, "{}", self.source_line)?;
+        write!(f, "{}{}", " ".repeat(self.column - 1), "^".repeat(width))
+    }
+}
+
+impl Error for ParseError {}
+
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    #[test]
+    fn test_error_position() {
+        let source = "1 2 +\n: foo ; ;\n";
+        let err = ParseError::new(
+            ParseErrorKind::UnexpectedToken,
+            "unexpected \";\"".to_string(),
+            &Loc::new(source, Span::new(14, 15), None),
+            source,
+        );
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 9);
+        assert_eq!(err.source_line, ": foo ; ;");
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 9: unexpected \";\"\n: foo ; ;\n        ^"
+        );
+    }
+
+    #[test]
+    fn test_error_display_with_file() {
+        let source = "1 2 ;";
+        let file: Rc<str> = Rc::from("script.sltf");
+        let err = ParseError::new(
+            ParseErrorKind::UnexpectedToken,
+            "unexpected \";\"".to_string(),
+            &Loc::new(source, Span::new(4, 5), Some(file)),
+            source,
+        );
+        assert_eq!(
+            err.to_string(),
+            "script.sltf, line 1, column 5: unexpected \";\"\n1 2 ;\n    ^"
+        );
+    }
+}
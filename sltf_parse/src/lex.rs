@@ -1,104 +1,495 @@
 extern crate regex;
 use regex::Regex;
 
-use super::tok_ast::{Tok, Prim};
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+
+use super::error::{ParseError, ParseErrorKind};
+use super::loc::{Locator, Span};
+use super::tok_ast::{Tok, Token, Prim};
 use super::tok_ast::Tok::*;
 
 pub struct Lexer {
     token: regex::Regex,
     number: regex::Regex,
+    radix_number: regex::Regex,
+    ratio: regex::Regex,
+    float: regex::Regex,
+    character: regex::Regex,
     symbol: regex::Regex,
     string: regex::Regex,
+    long_string: regex::Regex,
+    raw_string: regex::Regex,
+    unterminated_string: regex::Regex,
+    comment: regex::Regex,
+    unterminated_comment: regex::Regex,
+    shebang: regex::Regex,
     delimiter: regex::Regex,
+    // Whether `tokenize` returns comments (as `CommentTok`s) rather than
+    // dropping them; the parser skips them either way.
+    keep_comments: bool,
 }
 
 fn strict_regex(regex_str: &str) -> Regex {
     let mut modified = String::new();
-    modified.push('^');
+    modified.push_str("^(?:");
     modified.push_str(regex_str);
-    modified.push('$');
+    modified.push_str(")$");
     Regex::new(modified.as_ref())
         .expect("Failed to compile regex")
 }
 
+// Ints and ratios may use `_` to separate groups of digits, as in
+// `1_000_000`.
+const NUMBER: &str = r"-?[0-9][0-9_]*";
+// An int in another base: `0x1F`, `0b1010` and `0o17`, or Forth style `$FF`
+// (hex), `%1010` (binary) and `#10` (decimal). We take any run of letters
+// and digits after the prefix, so that a bad digit is reported rather than
+// quietly starting a new token.
+const RADIX_NUMBER: &str = r"-?(?:0[xXbBoO][0-9a-zA-Z_]*|\$[0-9a-fA-F][0-9a-zA-Z_]*|[%#][0-9][0-9a-zA-Z_]*)";
+// An exact fraction such as `1/3` or `-22/7`.
+const RATIO: &str = r"-?[0-9][0-9_]*/[0-9][0-9_]*";
+// A float needs a fractional part or an exponent (or both), so that plain
+// integers stay ints. The fractional part may stand alone, as in `-.5`.
+const FLOAT: &str = r"-?(?:(?:[0-9]+\.[0-9]+|\.[0-9]+)(?:[eE][-+]?[0-9]+)?|[0-9]+[eE][-+]?[0-9]+)";
+// The non-finite floats are written by name. Like any number they have to
+// be the whole token, so e.g. `info` stays a symbol.
+const FLOAT_NAME: &str = r"-?(?:inf|nan)";
+// A character literal such as `'a'`, which stands for its code point. The
+// quotes may hold a single space, or any run of other characters (so that
+// e.g. `'ab'` is reported rather than lexed as a symbol).
+const CHARACTER: &str = r"'(?:\s|(?:\\.|[^\\'\s])+)'";
+// Like Forth, we split the input at whitespace (and around strings), and a
+// token is only a number if the whole of it reads as one; anything else is
+// a word. So `2dup`, `1+` and `0=` are all ordinary words.
+// Control characters aren't allowed in a symbol, since they're almost
+// certainly a mistake (and can't be seen).
+const SYMBOL: &str = r#"[^"\s\p{Cc}]+"#;
+// The one kind of token that holds whitespace, other than a string.
+const SPACE_CHARACTER: &str = r"'\s'";
+// Strings may run over several lines. Escapes are decoded by `unescape`.
+const STRING: &str = r#""(?:\\.|[^\\"])*""#;
+// A long string, `"""..."""`, may also hold `"` and `""` without escaping
+// them. A newline straight after the opening quotes is dropped, so that the
+// text can start on a line of its own.
+const LONG_STRING: &str = r#""""(?:"?"?(?:\\.|[^\\"]))*""""#;
+// A raw string, `r"..."`, has no escapes. To hold a `"`, use `r#"..."#`,
+// which ends at the first `"#`.
+const RAW_STRING: &str = r##"r"[^"]*"|r#"(?:[^"]|"+[^"#])*"+#"##;
+// A string that is still open at the end of the input. We lex it as a token
+// (and report it) rather than skipping it, because in the repl the rest of
+// the string may arrive on the next line. It's tried before STRING, since
+// e.g. an open `"""` starts with a complete `""`.
+const UNTERMINATED_STRING: &str = concat!(
+    r#""""(?:"?"?(?:\\.|[^\\"]))*"?"?\\?\z"#,
+    r#"|"(?:\\.|[^\\"])*\\?\z"#,
+    r##"|r"[^"]*\z|r#"(?:[^"]|"+[^"#])*"*\z"##,
+);
+// As in Forth, `(` and `\` are only comments when they are whole tokens
+// (so `(foo)` is an ordinary word). A `(` comment runs to the next `)`,
+// and a `\` comment to the end of the line.
+const COMMENT: &str = r"\(\s[^)]*\)|\\(?:[^\S\n][^\n]*|(?m:$))";
+const UNTERMINATED_COMMENT: &str = r"\((?:\s[^)]*)?\z";
+// A `#!` line at the very start of the input, so that scripts can be run
+// directly.
+const SHEBANG: &str = r"^#![^\n]*";
+const DELIMITER: &str = r";|::?";
+
+impl Default for Lexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Lexer {
 
     pub fn new() -> Self {
-        let NUMBER: &str = r"-?[0-9]+";
-        let SYMBOL: &str = r#"[^"0-9\s][^"\s]*"#;
-        let STRING: &str = r#""((?:\\.|[^\\"])*)""#;
-        let STRING_NOCAP: &str = r#""(?:\\.|[^\\"])*""#;
-        let DELIMITER: &str = r";|:";
         let number = strict_regex(NUMBER);
+        let radix_number = strict_regex(RADIX_NUMBER);
+        let ratio = strict_regex(RATIO);
+        let float = strict_regex(&format!("{}|{}", FLOAT, FLOAT_NAME));
+        let character = strict_regex(CHARACTER);
         let symbol = strict_regex(SYMBOL);
         let string = strict_regex(STRING);
+        let long_string = strict_regex(LONG_STRING);
+        let raw_string = strict_regex(RAW_STRING);
+        let unterminated_string = strict_regex(UNTERMINATED_STRING);
+        let comment = strict_regex(COMMENT);
+        let unterminated_comment = strict_regex(UNTERMINATED_COMMENT);
+        let shebang = Regex::new(SHEBANG).expect("Failed to compile regex");
         let delimiter = strict_regex(DELIMITER);
         let mut token_str = String::new();
-        token_str.push_str(r"\s*(");
-        token_str.push_str(NUMBER);
-        token_str.push_str("|");
+        token_str.push_str(r"^(");
+        token_str.push_str(SPACE_CHARACTER);
+        token_str.push('|');
+        token_str.push_str(LONG_STRING);
+        token_str.push('|');
+        token_str.push_str(RAW_STRING);
+        token_str.push('|');
+        token_str.push_str(UNTERMINATED_STRING);
+        token_str.push('|');
+        token_str.push_str(STRING);
+        token_str.push('|');
+        token_str.push_str(COMMENT);
+        token_str.push('|');
+        token_str.push_str(UNTERMINATED_COMMENT);
+        token_str.push('|');
         token_str.push_str(SYMBOL);
-        token_str.push_str("|");
-        token_str.push_str(STRING_NOCAP);
-        token_str.push_str("|");
-        token_str.push_str(DELIMITER);
-        token_str.push_str(r")");
+        token_str.push(')');
         let token = Regex::new(token_str.as_ref()).expect("Failed to compile regex");
         Lexer {
-            token, number, symbol, string, delimiter,
+            token, number, radix_number, ratio, float, character, symbol, string,
+            long_string, raw_string, unterminated_string, comment, unterminated_comment,
+            shebang, delimiter, keep_comments: false,
         }
     }
 
-    pub fn tokenize(&self, input: &str) -> Vec<Tok> {
-        self.token.captures_iter(input) 
-            .map(|cap| cap[1].to_owned())
-            .map(|raw| self.tokenize_one(raw))
-            .collect()
+    pub fn set_keep_comments(&mut self, keep: bool) {
+        self.keep_comments = keep;
+    }
+
+    // Split `input` into tokens. `file`, if given, names the source in
+    // the tokens' locations. Every character of the input is either
+    // whitespace or part of a token; anything else is reported, one error
+    // for each unrecognized stretch of input.
+    pub fn tokenize(&self, input: &str, file: Option<Rc<str>>) -> Result<Vec<Token>, Vec<ParseError>> {
+        let mut locator = Locator::new(input, file);
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        let mut pos = 0;
+        if let Some(shebang) = self.shebang.find(input) {
+            if self.keep_comments {
+                let loc = locator.locate(Span::new(0, shebang.end()));
+                tokens.push(Token { tok: CommentTok(shebang.as_str().to_string()), loc });
+            }
+            pos = shebang.end();
+        }
+        loop {
+            pos = input.len() - input[pos..].trim_start().len();
+            if pos == input.len() {
+                break;
+            }
+            let end = match self.token.captures(&input[pos..]) {
+                Some(cap) => pos + cap.get(1).expect("Token regex always captures group 1").end(),
+                None => {
+                    let end = self.unrecognized_end(input, pos);
+                    let loc = locator.locate(Span::new(pos, end));
+                    let message = format!("unrecognized input {:?}", &input[pos..end]);
+                    errors.push(ParseError::new(ParseErrorKind::UnrecognizedInput, message, &loc, input));
+                    pos = end;
+                    continue;
+                }
+            };
+            let loc = locator.locate(Span::new(pos, end));
+            match self.tokenize_one(&input[pos..end]) {
+                Ok(CommentTok(_)) if !self.keep_comments => (),
+                Ok(tok) => tokens.push(Token { tok, loc }),
+                Err(TokError { kind, message, span: None }) =>
+                    errors.push(ParseError::new(kind, message, &loc, input)),
+                Err(TokError { kind, message, span: Some(span) }) => {
+                    let loc = locator.locate(Span::new(pos + span.start, pos + span.end));
+                    errors.push(ParseError::new(kind, message, &loc, input));
+                },
+            }
+            pos = end;
+        }
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Where a stretch of input that no token matches, starting at `start`,
+    // ends: at the next whitespace, or the next place a token could start.
+    fn unrecognized_end(&self, input: &str, start: usize) -> usize {
+        let mut chars = input[start..].char_indices().skip(1);
+        loop {
+            match chars.next() {
+                None => return input.len(),
+                Some((offset, ch)) => {
+                    let pos = start + offset;
+                    if ch.is_whitespace() || self.token.is_match(&input[pos..]) {
+                        return pos;
+                    }
+                }
+            }
+        }
     }
 
-    fn tokenize_one(&self, raw_tok: String) -> Tok {
-        if self.delimiter.is_match_at(raw_tok.as_ref(), 0) {
-            self.tokenize_delim(raw_tok)
-        } else if self.number.is_match_at(raw_tok.as_ref(), 0) {
-            // NOTE: technically an overflow error probably causes a panic
-            let num: i64 = raw_tok.parse().expect("Failed to parse number");
-            LitTok(Prim::Int(num))
-        } else if self.symbol.is_match_at(raw_tok.as_ref(), 0) {
-            SymbolTok(raw_tok.to_owned())
-        } else if self.string.is_match_at(raw_tok.as_ref(), 0) {
-            self.tokenize_string(raw_tok)
+    fn tokenize_one(&self, raw_tok: &str) -> Result<Tok, TokError> {
+        if self.delimiter.is_match_at(raw_tok, 0) {
+            Ok(self.tokenize_delim(raw_tok))
+        } else if self.number.is_match_at(raw_tok, 0) || self.radix_number.is_match_at(raw_tok, 0) {
+            Ok(self.tokenize_int(raw_tok)?)
+        } else if self.ratio.is_match_at(raw_tok, 0) {
+            Ok(self.tokenize_ratio(raw_tok)?)
+        } else if self.float.is_match_at(raw_tok, 0) {
+            // Rust parses too-large literals as infinity; we only want
+            // that when it was asked for by name (a literal written with
+            // digits always ends in one).
+            match raw_tok.parse::<f64>() {
+                Ok(num) if num.is_finite() || !raw_tok.ends_with(char::is_numeric) =>
+                    Ok(LitTok(Prim::Float(num))),
+                _ => Err(TokError::from((
+                    ParseErrorKind::InvalidNumber,
+                    format!("float literal {} is out of range", raw_tok),
+                ))),
+            }
+        } else if self.character.is_match_at(raw_tok, 0) {
+            self.tokenize_character(raw_tok)
+        } else if self.raw_string.is_match_at(raw_tok, 0) {
+            let hashes = if raw_tok.starts_with("r#") { 1 } else { 0 };
+            let contents = &raw_tok[2 + hashes..raw_tok.len() - 1 - hashes];
+            Ok(LitTok(Prim::Str(contents.to_string())))
+        } else if self.comment.is_match_at(raw_tok, 0) {
+            Ok(CommentTok(raw_tok.to_owned()))
+        } else if self.unterminated_comment.is_match_at(raw_tok, 0) {
+            Err(TokError::from((
+                ParseErrorKind::UnterminatedComment,
+                "comment is missing its closing \")\"".to_string(),
+            )))
+        } else if self.symbol.is_match_at(raw_tok, 0) {
+            Ok(SymbolTok(raw_tok.to_owned()))
+        } else if self.long_string.is_match_at(raw_tok, 0) {
+            let contents = &raw_tok[3..raw_tok.len() - 3];
+            let skipped = if contents.starts_with("\r\n") {
+                2
+            } else if contents.starts_with('\n') {
+                1
+            } else {
+                0
+            };
+            let contents = unescape(&contents[skipped..]).map_err(|err| err.shifted(3 + skipped))?;
+            Ok(LitTok(Prim::Str(contents)))
+        } else if self.string.is_match_at(raw_tok, 0) {
+            let contents = unescape(&raw_tok[1..raw_tok.len() - 1]).map_err(|err| err.shifted(1))?;
+            Ok(LitTok(Prim::Str(contents)))
+        } else if self.unterminated_string.is_match_at(raw_tok, 0) {
+            Err(TokError::from((
+                ParseErrorKind::UnterminatedString,
+                "string literal is missing its closing \"".to_string(),
+            )))
         } else {
             unreachable!("Token didn't match any token type {:?}", raw_tok)
         }
     }
 
-    fn tokenize_delim(&self, raw_tok: String) -> Tok {
+    fn tokenize_int(&self, raw_tok: &str) -> Result<Tok, (ParseErrorKind, String)> {
+        let (negative, unsigned) = match raw_tok.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, raw_tok),
+        };
+        let (radix, digits) = split_radix(unsigned);
+        let digits = without_separators("integer", raw_tok, digits)?;
+        if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix)) {
+            return Err((
+                ParseErrorKind::InvalidNumber,
+                format!("integer literal {} has an invalid digit {:?}", raw_tok, bad),
+            ));
+        }
+        let value = BigInt::parse_bytes(digits.as_bytes(), radix)
+            .expect("Checked the digits above");
+        let value = if negative { -value } else { value };
+        match value.to_i64() {
+            Some(num) => Ok(LitTok(Prim::Int(num))),
+            None => Err((
+                ParseErrorKind::InvalidNumber,
+                format!("integer literal {} is out of range", raw_tok),
+            )),
+        }
+    }
+
+    // A ratio is reduced to lowest terms, and one that turns out to be a
+    // whole number (e.g. `4/2`) becomes an int.
+    fn tokenize_ratio(&self, raw_tok: &str) -> Result<Tok, (ParseErrorKind, String)> {
+        let (numer, denom) = raw_tok.split_at(raw_tok.find('/').expect("Ratios contain a /"));
+        let (negative, numer) = match numer.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, numer),
+        };
+        let numer: BigInt = without_separators("ratio", raw_tok, numer)?.parse()
+            .expect("Checked the digits with a regex");
+        let numer = if negative { -numer } else { numer };
+        let denom: BigInt = without_separators("ratio", raw_tok, &denom[1..])?.parse()
+            .expect("Checked the digits with a regex");
+        if denom.is_zero() {
+            return Err((
+                ParseErrorKind::InvalidNumber,
+                format!("ratio literal {} has a zero denominator", raw_tok),
+            ));
+        }
+        let ratio = BigRational::new(numer, denom);
+        if !ratio.is_integer() {
+            Ok(LitTok(Prim::Ratio(ratio)))
+        } else {
+            match ratio.to_integer().to_i64() {
+                Some(num) => Ok(LitTok(Prim::Int(num))),
+                None => Err((
+                    ParseErrorKind::InvalidNumber,
+                    format!("ratio literal {} is out of range", raw_tok),
+                )),
+            }
+        }
+    }
+
+    // A character literal is just another way to write an int: the
+    // character's code point. It takes the same escapes as a string.
+    fn tokenize_character(&self, raw_tok: &str) -> Result<Tok, TokError> {
+        let contents = unescape(&raw_tok[1..raw_tok.len() - 1]).map_err(|err| err.shifted(1))?;
+        let mut chars = contents.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Ok(LitTok(Prim::Int(i64::from(u32::from(ch))))),
+            _ => Err(TokError::from((
+                ParseErrorKind::InvalidCharacter,
+                format!("character literal {} must hold exactly one character", raw_tok),
+            ))),
+        }
+    }
+
+    fn tokenize_delim(&self, raw_tok: &str) -> Tok {
         if raw_tok == ";" {
             SemiColon
         } else if raw_tok == ":" {
             Colon
+        } else if raw_tok == "::" {
+            DoubleColon
         } else {
             unreachable!("Did not expect unknown delimiter, parsing {:?}", raw_tok)
         }
     }
 
-    fn tokenize_string(&self, raw_tok: String) -> Tok {
-        let cap = self.string.captures_iter(raw_tok.as_ref()).next()
-                .expect("Did not expect non-capture with string");
-        let contents = match cap.get(1) {
-            Some(mtch) => {
-                mtch.as_str().to_string()
-                    .replace(r#"\n"#, "\n")
-                    .replace(r#"\t"#, "\t")
-                    .replace(r#"\r"#, "\r")
-                    .replace(r#"\\"#, "\\")
-                    .replace(r#"\""#, "\"")
-            }
-            None => String::new(),
+}
+
+// A problem with a single token. Most are blamed on the whole token, but
+// e.g. a bad escape is blamed on just the escape: `span`, if given, is
+// relative to the start of the token.
+struct TokError {
+    kind: ParseErrorKind,
+    message: String,
+    span: Option<Span>,
+}
+
+impl TokError {
+
+    // Move the span along by `offset`, e.g. to skip a string's opening
+    // quote.
+    fn shifted(mut self, offset: usize) -> Self {
+        self.span = self.span.map(|span| Span::new(span.start + offset, span.end + offset));
+        self
+    }
+}
+
+impl From<(ParseErrorKind, String)> for TokError {
+    fn from((kind, message): (ParseErrorKind, String)) -> Self {
+        TokError { kind, message, span: None }
+    }
+}
+
+// Decode the escapes in the contents of a string (or character) literal,
+// in a single pass so that e.g. `\\n` is a backslash followed by an `n`.
+// The escapes are `\n`, `\t`, `\r`, `\0`, `\\`, `\"` and `\'`, plus `\xNN`
+// for an ASCII character and `\u{NNNN}` for any unicode scalar value.
+fn unescape(contents: &str) -> Result<String, TokError> {
+    let mut unescaped = String::with_capacity(contents.len());
+    let mut chars = contents.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        let invalid = |end: usize, message: String| TokError {
+            kind: ParseErrorKind::InvalidEscape,
+            message,
+            span: Some(Span::new(start, end)),
         };
-        LitTok(Prim::Str(contents))
+        let (escaped_at, escaped) = match chars.next() {
+            Some(next) => next,
+            None => return Err(invalid(contents.len(), "escape is missing its character".to_string())),
+        };
+        let mut end = escaped_at + escaped.len_utf8();
+        let decoded = match escaped {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' | '"' | '\'' => escaped,
+            'x' => {
+                let digits: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                end += digits.len();
+                let hex = digits.len() == 2 && digits.chars().all(|c| c.is_ascii_hexdigit());
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(code) if hex && code <= 0x7F => char::from(code),
+                    Ok(_) if hex => return Err(invalid(end,
+                        format!("\\x{} is not an ASCII character; use \\u{{{}}}", digits, digits))),
+                    _ => return Err(invalid(end,
+                        "\\x escape needs two hex digits".to_string())),
+                }
+            },
+            'u' => {
+                let mut digits = String::new();
+                let mut closed = false;
+                if let Some(&(_, '{')) = chars.peek() {
+                    chars.next();
+                    end += 1;
+                    for (_, c) in chars.by_ref() {
+                        end += c.len_utf8();
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        digits.push(c);
+                    }
+                }
+                let hex = digits.chars().all(|c| c.is_ascii_hexdigit());
+                if !closed || !hex || digits.is_empty() || digits.len() > 6 {
+                    return Err(invalid(end,
+                        "\\u escape needs one to six hex digits in braces, as in \\u{e9}".to_string()));
+                }
+                match u32::from_str_radix(&digits, 16).ok().and_then(std::char::from_u32) {
+                    Some(c) => c,
+                    None => return Err(invalid(end,
+                        format!("\\u{{{}}} is not a unicode scalar value", digits))),
+                }
+            },
+            other => return Err(invalid(end, format!("unknown escape \\{}", other))),
+        };
+        unescaped.push(decoded);
     }
+    Ok(unescaped)
+}
 
+// Split the base prefix, if any, off an unsigned int literal.
+fn split_radix(unsigned: &str) -> (u32, &str) {
+    let prefixes = [("0x", 16), ("0X", 16), ("0b", 2), ("0B", 2), ("0o", 8), ("0O", 8),
+                    ("$", 16), ("%", 2), ("#", 10)];
+    for &(prefix, radix) in prefixes.iter() {
+        if let Some(digits) = unsigned.strip_prefix(prefix) {
+            return (radix, digits);
+        }
+    }
+    (10, unsigned)
+}
+
+// Remove the `_` separators from a run of digits. A separator has to sit
+// between two digits, and there has to be at least one digit.
+fn without_separators(kind: &str, raw_tok: &str, digits: &str) -> Result<String, (ParseErrorKind, String)> {
+    if digits.is_empty() {
+        return Err((
+            ParseErrorKind::InvalidNumber,
+            format!("{} literal {} has no digits", kind, raw_tok),
+        ));
+    }
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err((
+            ParseErrorKind::InvalidNumber,
+            format!("{} literal {} has a misplaced _ separator", kind, raw_tok),
+        ));
+    }
+    Ok(digits.replace('_', ""))
 }
 
 #[cfg(test)]
@@ -108,7 +499,11 @@ mod test_lexer {
 
     fn tcase(input: &str, expected: Vec<Tok>) {
         let lexer: Lexer = Lexer::new();
-        let actual = lexer.tokenize(input);
+        let actual: Vec<Tok> = lexer.tokenize(input, None)
+            .expect("Did not expect a lexing error")
+            .into_iter()
+            .map(|token| token.tok)
+            .collect();
         assert_eq!(actual, expected)
     }
 
@@ -121,6 +516,8 @@ mod test_lexer {
         tcase(": ", vec![Colon]);
         tcase("   :  ", vec![Colon]);
         tcase("   :  ; ", vec![Colon, SemiColon]);
+        tcase(":: ;", vec![DoubleColon, SemiColon]);
+        tcase("::: :;", vec![SymbolTok(":::".to_string()), SymbolTok(":;".to_string())]);
     }
 
 
@@ -135,6 +532,131 @@ mod test_lexer {
                 ]);
     }
 
+    #[test]
+    fn test_tokenize_radix_number() {
+        tcase("0x1F 0X1f 0b1010 0o17 -0x10",
+            vec![ LitTok(Prim::Int(31))
+                , LitTok(Prim::Int(31))
+                , LitTok(Prim::Int(10))
+                , LitTok(Prim::Int(15))
+                , LitTok(Prim::Int(-16))
+                ]);
+        tcase("$FF %1010 #10 -$ff",
+            vec![ LitTok(Prim::Int(255))
+                , LitTok(Prim::Int(10))
+                , LitTok(Prim::Int(10))
+                , LitTok(Prim::Int(-255))
+                ]);
+        tcase("1_000_000 0xFFFF_FFFF 1_000/3",
+            vec![ LitTok(Prim::Int(1_000_000))
+                , LitTok(Prim::Int(0xFFFF_FFFF))
+                , LitTok(Prim::Ratio(BigRational::new(BigInt::from(1000), BigInt::from(3))))
+                ]);
+        tcase("0x7FFFFFFFFFFFFFFF -0x8000000000000000",
+            vec![LitTok(Prim::Int(i64::MAX)), LitTok(Prim::Int(i64::MIN))]);
+        tcase("$ % # #foo",
+            vec![ SymbolTok("$".to_string())
+                , SymbolTok("%".to_string())
+                , SymbolTok("#".to_string())
+                , SymbolTok("#foo".to_string())
+                ]);
+    }
+
+    #[test]
+    fn test_tokenize_malformed_number() {
+        let lexer: Lexer = Lexer::new();
+        let message = |input: &str| {
+            let errors = lexer.tokenize(input, None).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].kind, ParseErrorKind::InvalidNumber);
+            errors[0].message.clone()
+        };
+        assert_eq!(message("0x"), "integer literal 0x has no digits");
+        assert_eq!(message("0b102"), "integer literal 0b102 has an invalid digit '2'");
+        assert_eq!(message("0o8"), "integer literal 0o8 has an invalid digit '8'");
+        assert_eq!(message("$FG"), "integer literal $FG has an invalid digit 'G'");
+        assert_eq!(message("%12"), "integer literal %12 has an invalid digit '2'");
+        assert_eq!(message("1__000"), "integer literal 1__000 has a misplaced _ separator");
+        assert_eq!(message("1000_"), "integer literal 1000_ has a misplaced _ separator");
+        assert_eq!(message("0x_FF"), "integer literal 0x_FF has a misplaced _ separator");
+        assert_eq!(message("1_/3"), "ratio literal 1_/3 has a misplaced _ separator");
+        assert_eq!(message("0x1_0000_0000_0000_0000"),
+                   "integer literal 0x1_0000_0000_0000_0000 is out of range");
+    }
+
+    #[test]
+    fn test_tokenize_character() {
+        tcase("'a' 'Z' ' ' '\\n' '\\'' '\\\\' 'é'",
+            vec![ LitTok(Prim::Int(97))
+                , LitTok(Prim::Int(90))
+                , LitTok(Prim::Int(32))
+                , LitTok(Prim::Int(10))
+                , LitTok(Prim::Int(39))
+                , LitTok(Prim::Int(92))
+                , LitTok(Prim::Int(233))
+                ]);
+        tcase("don't 'quote",
+            vec![SymbolTok("don't".to_string()), SymbolTok("'quote".to_string())]);
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize("'ab' '\\q'", None).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidCharacter);
+        assert_eq!(errors[0].message, "character literal 'ab' must hold exactly one character");
+        assert_eq!(errors[1].kind, ParseErrorKind::InvalidEscape);
+        assert_eq!(errors[1].message, "unknown escape \\q");
+        assert_eq!(errors[1].span, Span::new(6, 8));
+        tcase("'\\x41' '\\u{1F600}'", vec![LitTok(Prim::Int(0x41)), LitTok(Prim::Int(0x1F600))]);
+    }
+
+    #[test]
+    fn test_tokenize_float() {
+        tcase("2.75 -2.5 1e-9 -.5 2E3",
+            vec![ LitTok(Prim::Float(2.75))
+                , LitTok(Prim::Float(-2.5))
+                , LitTok(Prim::Float(1e-9))
+                , LitTok(Prim::Float(-0.5))
+                , LitTok(Prim::Float(2000.0))
+                ]);
+        tcase("inf -inf . info",
+            vec![ LitTok(Prim::Float(f64::INFINITY))
+                , LitTok(Prim::Float(f64::NEG_INFINITY))
+                , SymbolTok(".".to_string())
+                , SymbolTok("info".to_string())
+                ]);
+        let lexer: Lexer = Lexer::new();
+        let tokens = lexer.tokenize("nan", None).unwrap();
+        match tokens[0].tok {
+            LitTok(Prim::Float(x)) => assert!(x.is_nan()),
+            ref other => panic!("expected nan, got {:?}", other),
+        }
+        let errors = lexer.tokenize("1.5 1e999", None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidNumber);
+        assert_eq!(errors[0].span, Span::new(4, 9));
+    }
+
+    #[test]
+    fn test_tokenize_ratio() {
+        let ratio = |numer: i64, denom: i64| {
+            LitTok(Prim::Ratio(BigRational::new(BigInt::from(numer), BigInt::from(denom))))
+        };
+        tcase("1/3 -22/7 6/4 4/2",
+            vec![ ratio(1, 3)
+                , ratio(-22, 7)
+                , ratio(3, 2)
+                , LitTok(Prim::Int(2))
+                ]);
+        tcase("100000000000000000000/3", vec![LitTok(Prim::Ratio(BigRational::new(
+            "100000000000000000000".parse().unwrap(),
+            BigInt::from(3),
+        )))]);
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize("1/0 100000000000000000000/2", None).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|err| err.kind == ParseErrorKind::InvalidNumber));
+        assert_eq!(errors[0].message, "ratio literal 1/0 has a zero denominator");
+    }
+
     #[test]
     fn test_tokenize_string() {
 
@@ -164,6 +686,96 @@ mod test_lexer {
         );
     }
 
+    #[test]
+    fn test_tokenize_string_escapes() {
+        let tok_str = |val: &str| LitTok(Prim::Str(val.to_string()));
+        tcase(r#""a\nb\tc\rd\0e""#, vec![tok_str("a\nb\tc\rd\0e")]);
+        tcase(r#""\\n" "\\\"" "\'""#, vec![tok_str("\\n"), tok_str("\\\""), tok_str("'")]);
+        tcase(r#""\x41\x7e" "caf\u{e9}" "\u{1F600}""#,
+            vec![tok_str("A~"), tok_str("caf\u{e9}"), tok_str("\u{1F600}")]);
+    }
+
+    #[test]
+    fn test_tokenize_invalid_escapes() {
+        let lexer: Lexer = Lexer::new();
+        let error = |input: &str| {
+            let errors = lexer.tokenize(input, None).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].kind, ParseErrorKind::InvalidEscape);
+            (errors[0].message.clone(), errors[0].span)
+        };
+        assert_eq!(error(r#"1 "ab\qc""#), ("unknown escape \\q".to_string(), Span::new(5, 7)));
+        assert_eq!(error(r#""\x4""#).1, Span::new(1, 4));
+        assert_eq!(error(r#""\x+1""#).0, "\\x escape needs two hex digits");
+        assert_eq!(error(r#""\xe9""#).0, "\\xe9 is not an ASCII character; use \\u{e9}");
+        assert_eq!(error(r#""\u41""#).1, Span::new(1, 3));
+        assert_eq!(error(r#""\u{}" "#).1, Span::new(1, 5));
+        assert_eq!(error(r#""\u{1234567}""#).0,
+                   "\\u escape needs one to six hex digits in braces, as in \\u{e9}");
+        assert_eq!(error(r#""x\u{D800}y""#),
+                   ("\\u{D800} is not a unicode scalar value".to_string(), Span::new(2, 10)));
+        let errors = lexer.tokenize("\"one\n\\z\"", None).unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column), (2, 1));
+    }
+
+    #[test]
+    fn test_tokenize_raw_and_long_strings() {
+        let tok_str = |val: &str| LitTok(Prim::Str(val.to_string()));
+        tcase(r#"r"C:\dir\n" r"" r"a"b"#,
+            vec![tok_str("C:\\dir\\n"), tok_str(""), tok_str("a"), SymbolTok("b".to_string())]);
+        tcase(r###"r#"say "hi""# r#"a""#"###, vec![tok_str("say \"hi\""), tok_str("a\"")]);
+        tcase("\"\"\"\nShe said \"hi\",\n\"\"twice\"\".\\n\"\"\" x",
+            vec![tok_str("She said \"hi\",\n\"\"twice\"\".\n"), SymbolTok("x".to_string())]);
+        tcase("\"\"\"one line\"\"\" \"\"", vec![tok_str("one line"), tok_str("")]);
+        let lexer: Lexer = Lexer::new();
+        for input in &["\"\"\"open\n\"", "r\"open", "r#\"open\"", "\"\"\"\\"] {
+            let errors = lexer.tokenize(input, None).unwrap_err();
+            assert_eq!(errors.len(), 1, "{:?}", input);
+            assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedString);
+        }
+    }
+
+    #[test]
+    fn test_tokenize_comments() {
+        tcase("1 ( n -- n ) 2 \\ the rest\n3 \\\n4 (no) \\no ()",
+            vec![ LitTok(Prim::Int(1))
+                , LitTok(Prim::Int(2))
+                , LitTok(Prim::Int(3))
+                , LitTok(Prim::Int(4))
+                , SymbolTok("(no)".to_string())
+                , SymbolTok("\\no".to_string())
+                , SymbolTok("()".to_string())
+                ]);
+        tcase("( spans\ntwo lines \"quoted\" )1 \\ ( not a block", vec![LitTok(Prim::Int(1))]);
+        tcase("#!/usr/bin/env sltf\n1 #! 2", vec![
+            LitTok(Prim::Int(1)),
+            SymbolTok("#!".to_string()),
+            LitTok(Prim::Int(2)),
+        ]);
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize("1 ( open", None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedComment);
+        assert_eq!(errors[0].span, Span::new(2, 8));
+    }
+
+    #[test]
+    fn test_tokenize_keeping_comments() {
+        let mut lexer: Lexer = Lexer::new();
+        lexer.set_keep_comments(true);
+        let tokens: Vec<(Tok, Span)> = lexer.tokenize("#! sltf\ndup ( a -- a a ) \\ copy\n", None)
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.tok, token.loc.span))
+            .collect();
+        assert_eq!(tokens, vec![
+            (CommentTok("#! sltf".to_string()), Span::new(0, 7)),
+            (SymbolTok("dup".to_string()), Span::new(8, 11)),
+            (CommentTok("( a -- a a )".to_string()), Span::new(12, 24)),
+            (CommentTok("\\ copy".to_string()), Span::new(25, 31)),
+        ]);
+    }
+
     #[test]
     fn test_tokenize_symbol() {
 
@@ -183,5 +795,112 @@ mod test_lexer {
             r#"my-symbol %*+"#,
             vec![tok_sym("my-symbol"), tok_sym("%*+")],
         );
+        tcase(
+            r#";; :x"#,
+            vec![tok_sym(";;"), tok_sym(":x")],
+        );
+    }
+
+    #[test]
+    fn test_tokenize_digit_words() {
+
+        fn tok_sym(val: &str) -> Tok {
+            SymbolTok(val.to_string())
+        }
+
+        tcase(
+            "1+ 1- 2* 2/ 0= 0< 2dup 2>r 2r> 3.14.15 1e5x 12abc -1-",
+            ["1+", "1-", "2*", "2/", "0=", "0<", "2dup", "2>r", "2r>", "3.14.15", "1e5x", "12abc", "-1-"]
+                .iter().map(|name| tok_sym(name)).collect(),
+        );
+        tcase(
+            "1 +1 'a'b 1/3x :x;",
+            vec![ LitTok(Prim::Int(1))
+                , tok_sym("+1")
+                , tok_sym("'a'b")
+                , tok_sym("1/3x")
+                , tok_sym(":x;")
+                ],
+        );
+        tcase(
+            r#"1"a"2"#,
+            vec![ LitTok(Prim::Int(1))
+                , LitTok(Prim::Str("a".to_string()))
+                , LitTok(Prim::Int(2))
+                ],
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tokenize_spans() {
+        let lexer: Lexer = Lexer::new();
+        let spans: Vec<Span> = lexer.tokenize(r#" 12 "ab" foo"#, None)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.loc.span)
+            .collect();
+        assert_eq!(spans, vec![Span::new(1, 3), Span::new(4, 8), Span::new(9, 12)]);
+    }
+
+    #[test]
+    fn test_tokenize_locations() {
+        let lexer: Lexer = Lexer::new();
+        let file: Rc<str> = Rc::from("lines.sltf");
+        let locs: Vec<(Option<Rc<str>>, usize, usize)> = lexer
+            .tokenize("1 2\n  3\n\n: x ;", Some(file.clone()))
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.loc.file, token.loc.line, token.loc.column))
+            .collect();
+        let some_file = Some(file);
+        assert_eq!(locs, vec![
+            (some_file.clone(), 1, 1),
+            (some_file.clone(), 1, 3),
+            (some_file.clone(), 2, 3),
+            (some_file.clone(), 4, 1),
+            (some_file.clone(), 4, 3),
+            (some_file.clone(), 4, 5),
+        ]);
+    }
+
+    #[test]
+    fn test_tokenize_number_overflow() {
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize("1 99999999999999999999 2", None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidNumber);
+        assert_eq!(errors[0].span, Span::new(2, 22));
+        assert_eq!(errors[0].column, 3);
+        tcase(
+            "9223372036854775807 -9223372036854775808",
+            vec![LitTok(Prim::Int(i64::MAX)), LitTok(Prim::Int(i64::MIN))],
+        );
+        let errors = lexer.tokenize("-9223372036854775809", None).unwrap_err();
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidNumber);
+    }
+
+    #[test]
+    fn test_tokenize_unrecognized_input() {
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize("1 \u{7}\u{7} dup\u{0}x\n\u{1b}", None).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|err| err.kind == ParseErrorKind::UnrecognizedInput));
+        let spans: Vec<Span> = errors.iter().map(|err| err.span).collect();
+        assert_eq!(spans, vec![Span::new(2, 4), Span::new(8, 9), Span::new(11, 12)]);
+        assert_eq!(errors[0].message, "unrecognized input \"\\u{7}\\u{7}\"");
+        assert_eq!((errors[2].line, errors[2].column), (2, 1));
+    }
+
+    #[test]
+    fn test_tokenize_unterminated_string() {
+        let lexer: Lexer = Lexer::new();
+        let errors = lexer.tokenize(r#"1 "ok" "not ok"#, None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedString);
+        assert_eq!(errors[0].span, Span::new(7, 14));
+        tcase(
+            "\"two\nlines\"",
+            vec![LitTok(Prim::Str("two\nlines".to_string()))],
+        );
+    }
+}
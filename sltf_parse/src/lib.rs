@@ -1,30 +1,98 @@
 #[cfg(test)] #[macro_use]
 extern crate pretty_assertions;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
+use std::mem;
+use std::rc::Rc;
+
+pub mod error;
 pub mod lex;
+pub mod loc;
 pub mod tok_ast;
 pub mod parse;
 
+pub use error::{ParseError, ParseErrorKind};
+pub use loc::{Loc, Span};
 pub use tok_ast::Ast;
 pub use tok_ast::Atom;
+pub use tok_ast::AtomKind;
 pub use tok_ast::Prim;
 
 
+// Parses whole sources with `parse`, or a source that arrives a piece at a
+// time (e.g. lines in the repl) with `feed`.
 pub struct Parser {
     lexer: lex::Lexer,
+    // Input passed to `feed` that ends in the middle of a definition, a
+    // string or a comment; we hold on to it until the rest of the construct
+    // arrives.
+    pending: String,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Parser {
 
     pub fn new() -> Self {
-        Parser { lexer: lex::Lexer::new() }
+        Parser { lexer: lex::Lexer::new(), pending: String::new() }
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
+        self.parse_source(input, None)
+    }
+
+    // Like `parse`, but the locations in the result (and in any errors)
+    // name `file` as their source.
+    pub fn parse_file(&self, file: &str, input: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
+        self.parse_source(input, Some(Rc::from(file)))
     }
 
-    pub fn parse(&self, input: &str) -> Vec<Ast> {
-        let tokens = self.lexer.tokenize(input);
-        let ast = parse::tokens_to_ast(&tokens);
-        ast
+    // Parse the next piece of an incremental source. Anything complete is
+    // returned right away, but if the input stops in the middle of a word
+    // definition, string literal or comment, that part is held back and parsed
+    // together with the next piece (check `is_pending` to find out).
+    pub fn feed(&mut self, input: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
+        let mut source = mem::take(&mut self.pending);
+        source.push_str(input);
+        // Work backwards until we find a prefix of the source that parses:
+        // each time the parse fails only because something is unfinished,
+        // retry with everything before the unfinished construct.
+        let mut end = source.len();
+        loop {
+            match self.parse(&source[..end]) {
+                Ok(ast) => {
+                    if end < source.len() {
+                        self.pending = source[end..].to_string();
+                        self.pending.push('\n');
+                    }
+                    return Ok(ast);
+                },
+                Err(errors) => {
+                    if !errors.iter().all(|err| err.kind.is_incomplete()) {
+                        return Err(errors);
+                    }
+                    end = errors.iter().map(|err| err.span.start).min()
+                        .expect("Parsing failed without any errors");
+                },
+            }
+        }
+    }
+
+    // Whether the input so far ended in an unfinished construct.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn parse_source(&self, input: &str, file: Option<Rc<str>>) -> Result<Vec<Ast>, Vec<ParseError>> {
+        let tokens = self.lexer.tokenize(input, file)?;
+        parse::tokens_to_ast(&tokens, input)
     }
 }
 
@@ -33,5 +101,126 @@ impl Parser {
 mod test_parser {
     use super ::*;
 
+    // Drop the locations from a parse result, to compare its structure.
+    fn plain(result: Result<Vec<Ast>, Vec<ParseError>>) -> Result<Vec<Ast>, Vec<ParseError>> {
+        result.map(|ast| ast.into_iter().map(Ast::without_locs).collect())
+    }
+
+    #[test]
+    fn test_parse_definition() {
+        let parser = Parser::new();
+        assert_eq!(
+            plain(parser.parse(": square dup * ; 3 square")),
+            Ok(vec![
+                Ast::WordDef(
+                    "square".to_string(),
+                    vec![
+                        Atom::symbol("dup", Loc::default()),
+                        Atom::symbol("*", Loc::default()),
+                    ],
+                    Loc::default(),
+                ),
+                Ast::Simple(Atom::lit(Prim::Int(3), Loc::default())),
+                Ast::Simple(Atom::symbol("square", Loc::default())),
+            ])
+        );
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_parse_error_display() {
+        let parser = Parser::new();
+        let errors = parser.parse("1 2 +\n: sq dup * ; ;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "line 2, column 14: unexpected \";\" outside of a word definition\n\
+             : sq dup * ; ;\n\
+             \x20            ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_file_error() {
+        let parser = Parser::new();
+        let errors = parser.parse_file("bad.sltf", "1 ;").unwrap_err();
+        assert_eq!(errors[0].file, Some(Rc::from("bad.sltf")));
+        assert!(errors[0].to_string().starts_with("bad.sltf, line 1, column 3:"));
+    }
+
+    #[test]
+    fn test_feed_multiline_definition() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            plain(parser.feed("1 : square")),
+            Ok(vec![Ast::Simple(Atom::lit(Prim::Int(1), Loc::default()))])
+        );
+        assert!(parser.is_pending());
+        assert_eq!(parser.feed("  dup"), Ok(vec![]));
+        assert!(parser.is_pending());
+        let ast = parser.feed("  * ; square").unwrap();
+        assert!(!parser.is_pending());
+        assert_eq!(
+            plain(Ok(ast.clone())),
+            Ok(vec![
+                Ast::WordDef(
+                    "square".to_string(),
+                    vec![
+                        Atom::symbol("dup", Loc::default()),
+                        Atom::symbol("*", Loc::default()),
+                    ],
+                    Loc::default(),
+                ),
+                Ast::Simple(Atom::symbol("square", Loc::default())),
+            ])
+        );
+        // Lines are counted from the start of the definition.
+        match &ast[0] {
+            Ast::WordDef(_, body, _) => assert_eq!(body[1].loc.line, 3),
+            other => panic!("Expected a word definition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_feed_multiline_string() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(": greet \"hello,"), Ok(vec![]));
+        assert_eq!(plain(parser.feed("world\" ;")), Ok(vec![
+            Ast::WordDef(
+                "greet".to_string(),
+                vec![Atom::lit(Prim::Str("hello,\nworld".to_string()), Loc::default())],
+                Loc::default(),
+            ),
+        ]));
+        assert!(!parser.is_pending());
+    }
+
+    #[test]
+    fn test_feed_error_clears_pending() {
+        let mut parser = Parser::new();
+        parser.feed(": broken").unwrap();
+        assert!(parser.feed("dup ; ;").is_err());
+        assert!(!parser.is_pending());
+        assert_eq!(plain(parser.feed("2")), Ok(vec![Ast::Simple(Atom::lit(Prim::Int(2), Loc::default()))]));
+    }
+
+    #[test]
+    fn test_parse_skips_comments() {
+        let parser = Parser::new();
+        assert_eq!(
+            plain(parser.parse(": ( comment ) square ( n -- n*n ) dup * ; \\ done\n3 square")),
+            plain(parser.parse(": square dup * ; 3 square")),
+        );
+        let mut parser = Parser::new();
+        assert_eq!(plain(parser.feed("1 ( a comment")), Ok(vec![Ast::Simple(Atom::lit(Prim::Int(1), Loc::default()))]));
+        assert!(parser.is_pending());
+        assert_eq!(plain(parser.feed("that ends here ) 2")), Ok(vec![Ast::Simple(Atom::lit(Prim::Int(2), Loc::default()))]));
+        assert!(!parser.is_pending());
+    }
+
+    #[test]
+    fn test_parse_reports_unfinished_input() {
+        let parser = Parser::new();
+        let errors = parser.parse(": square dup *").unwrap_err();
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedDefinition);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::rc::Rc;
+
+
+// A region of the input, as byte offsets (end is exclusive).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+// Where a token or AST node came from: the (optional) name of the file,
+// the span, and the 1-based line and column of the start of the span.
+// Atoms that are part of a word definition also record the word's name,
+// which the vm uses to say which definition a runtime error came from.
+#[derive(Debug,PartialEq,Eq,Clone,Default)]
+pub struct Loc {
+    pub file: Option<Rc<str>>,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+    pub word: Option<Rc<str>>,
+}
+
+impl Loc {
+
+    // Locate a span from scratch. Prefer a `Locator` when locating many
+    // spans of the same source.
+    pub fn new(source: &str, span: Span, file: Option<Rc<str>>) -> Self {
+        Locator::new(source, file).locate(span)
+    }
+
+    pub fn in_word(mut self, word: &Rc<str>) -> Self {
+        self.word = Some(word.clone());
+        self
+    }
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file)?;
+        }
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+
+// Computes locations for a sequence of spans in increasing order, without
+// rescanning the source from the beginning each time.
+pub struct Locator<'a> {
+    source: &'a str,
+    file: Option<Rc<str>>,
+    offset: usize,
+    line: usize,
+    line_start: usize,
+}
+
+impl<'a> Locator<'a> {
+
+    pub fn new(source: &'a str, file: Option<Rc<str>>) -> Self {
+        Locator { source, file, offset: 0, line: 1, line_start: 0 }
+    }
+
+    pub fn locate(&mut self, span: Span) -> Loc {
+        if span.start < self.offset {
+            // Out of order; start over rather than give a wrong answer.
+            self.offset = 0;
+            self.line = 1;
+            self.line_start = 0;
+        }
+        for (i, c) in self.source[self.offset..span.start].char_indices() {
+            if c == '\n' {
+                self.line += 1;
+                self.line_start = self.offset + i + 1;
+            }
+        }
+        self.offset = span.start;
+        Loc {
+            file: self.file.clone(),
+            span,
+            line: self.line,
+            column: self.source[self.line_start..span.start].chars().count() + 1,
+            word: None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_loc {
+    use super::*;
+
+    #[test]
+    fn test_locator() {
+        let source = "1 2\n  é dup\n\nswap";
+        let mut locator = Locator::new(source, None);
+        let positions: Vec<(usize, usize)> = vec![(0, 1), (2, 3), (6, 8), (9, 12), (14, 18)]
+            .into_iter()
+            .map(|(start, end)| locator.locate(Span::new(start, end)))
+            .map(|loc| (loc.line, loc.column))
+            .collect();
+        assert_eq!(positions, vec![(1, 1), (1, 3), (2, 3), (2, 5), (4, 1)]);
+        let again = locator.locate(Span::new(2, 3));
+        assert_eq!((again.line, again.column), (1, 3));
+    }
+
+    #[test]
+    fn test_loc_display() {
+        let file: Rc<str> = Rc::from("square.sltf");
+        let loc = Loc::new(": square\n  dup * ;", Span::new(11, 14), Some(file));
+        assert_eq!(loc.to_string(), "square.sltf, line 2, column 3");
+    }
+}
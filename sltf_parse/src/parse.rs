@@ -1,69 +1,289 @@
-extern crate regex;
-use regex::Regex;
+use std::mem;
+use std::rc::Rc;
 
-use super::tok_ast::{Tok, Prim, Ast, Atom};
+use super::error::{ParseError, ParseErrorKind};
+use super::loc::Loc;
+use super::tok_ast::{Tok, Token, Ast, Atom, AtomKind, Prim};
 
 
 struct PartialDefinition {
-    name: String,
+    name: Rc<str>,
     words: Vec<Atom>,
+    // The location of the `:`; its span is extended to the `;` when we finish.
+    loc: Loc,
     finished: bool,
+    // Set for a `::` definition.
+    prog_word: bool,
 }
 
 enum DefinitionStatus {
     TopLevel,
-    WaitingForName,
+    // Set for `::` rather than `:`.
+    WaitingForName(Loc, bool),
     ProcessingBody(PartialDefinition),
+    // A definition we already reported an error for; skip to its `;`
+    // so that the body doesn't produce more (confusing) errors.
+    SkippingBody,
 }
 
-pub fn tokens_to_ast(tokens: &Vec<Tok>) -> Vec<Ast> {
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum BlockKind {
+    If,
+    // `conditional` is set for `?do`
+    Do { conditional: bool },
+    Begin,
+    // A quotation, `[ ... ]`
+    Quot,
+}
+
+impl BlockKind {
+    fn opener(self) -> &'static str {
+        match self {
+            BlockKind::If => "if",
+            BlockKind::Do { conditional: false } => "do",
+            BlockKind::Do { conditional: true } => "?do",
+            BlockKind::Begin => "begin",
+            BlockKind::Quot => "[",
+        }
+    }
+
+    // How to describe the word(s) that can close the block, in a message.
+    fn closer(self) -> &'static str {
+        match self {
+            BlockKind::If => "\"then\"",
+            BlockKind::Do { .. } => "\"loop\" or \"+loop\"",
+            BlockKind::Begin => "\"until\" or \"repeat\"",
+            BlockKind::Quot => "\"]\"",
+        }
+    }
+}
+
+// A control structure (e.g. `if ... else ... then`) that we're in the
+// middle of parsing. Atoms go into `current`; the words that divide a
+// structure into parts (e.g. `else`) move `current` onto `parts`.
+struct OpenBlock {
+    kind: BlockKind,
+    loc: Loc,
+    parts: Vec<Vec<Atom>>,
+    current: Vec<Atom>,
+}
+
+impl OpenBlock {
+
+    fn new(kind: BlockKind, loc: Loc) -> Self {
+        OpenBlock { kind, loc, parts: Vec::new(), current: Vec::new() }
+    }
+
+    fn split(&mut self) {
+        let part = mem::take(&mut self.current);
+        self.parts.push(part);
+    }
+
+    // Finish the block; `closer` is the word that closed it.
+    fn close(mut self, closer: &str) -> Atom {
+        self.split();
+        let mut parts = self.parts.into_iter();
+        let mut next_part = || parts.next().unwrap_or_default();
+        let kind = match self.kind {
+            BlockKind::If => {
+                let then_branch = next_part();
+                AtomKind::If(then_branch, next_part())
+            },
+            BlockKind::Do { conditional } => AtomKind::Do {
+                body: next_part(),
+                conditional,
+                plus: closer == "+loop",
+            },
+            BlockKind::Begin if closer == "until" =>
+                AtomKind::BeginUntil(next_part()),
+            BlockKind::Begin => {
+                let condition = next_part();
+                AtomKind::BeginWhile(condition, next_part())
+            },
+            // A quotation is just data: it goes on the stack like any
+            // other literal.
+            BlockKind::Quot => AtomKind::Lit(Prim::Quot(next_part())),
+        };
+        Atom::new(kind, self.loc)
+    }
+}
+
+const BLOCK_WORDS: &[&str] = &[
+    "if", "else", "then",
+    "do", "?do", "loop", "+loop",
+    "begin", "until", "while", "repeat",
+    "[", "]",
+];
+
+// The words that open, divide or close control structures. These are
+// handled by the parser rather than being looked up by the vm.
+fn is_block_word(tok: &Tok) -> bool {
+    match tok {
+        Tok::SymbolTok(sym) => BLOCK_WORDS.contains(&sym.as_str()),
+        _ => false,
+    }
+}
+
+// Process one of the `is_block_word` words, returning an atom if it
+// completed a control structure.
+fn block_word(blocks: &mut Vec<OpenBlock>, word: &str, loc: &Loc)
+              -> Result<Option<Atom>, (ParseErrorKind, String)> {
+    use self::BlockKind::*;
+
+    let innermost = blocks.last().map(|block| (block.kind, block.parts.len()));
+    let opened = match word {
+        "if" => Some(If),
+        "do" => Some(Do { conditional: false }),
+        "?do" => Some(Do { conditional: true }),
+        "begin" => Some(Begin),
+        "[" => Some(Quot),
+        _ => None,
+    };
+    if let Some(kind) = opened {
+        blocks.push(OpenBlock::new(kind, loc.clone()));
+        return Ok(None);
+    }
+    match (word, innermost) {
+        ("else", Some((If, 0))) | ("while", Some((Begin, 0))) => {
+            blocks.last_mut().expect("Checked for an innermost block above").split();
+            Ok(None)
+        },
+        ("then", Some((If, _))) |
+        ("loop", Some((Do { .. }, _))) | ("+loop", Some((Do { .. }, _))) |
+        ("until", Some((Begin, 0))) | ("repeat", Some((Begin, 1))) |
+        ("]", Some((Quot, _))) => {
+            let block = blocks.pop().expect("Checked for an innermost block above");
+            Ok(Some(block.close(word)))
+        },
+        (_, innermost) => {
+            let message = match innermost {
+                Some((kind, _)) => format!("unexpected {:?} inside {:?}", word, kind.opener()),
+                None => {
+                    let opener = match word {
+                        "else" | "then" => "if",
+                        "loop" | "+loop" => "do",
+                        "]" => "[",
+                        _ => "begin",
+                    };
+                    format!("{:?} without a matching {:?}", word, opener)
+                },
+            };
+            Err((ParseErrorKind::UnexpectedToken, message))
+        },
+    }
+}
+
+pub fn tokens_to_ast(tokens: &[Token], source: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
 
     use self::DefinitionStatus::*;
 
     let mut ast = Vec::new();
+    let mut errors = Vec::new();
     let mut status = TopLevel;
+    let mut blocks: Vec<OpenBlock> = Vec::new();
+
+    let mut error = |kind: ParseErrorKind, message: String, loc: &Loc| {
+        errors.push(ParseError::new(kind, message, loc, source))
+    };
 
-    for tok in tokens {
+    for Token { tok, loc } in tokens {
+        // Atoms inside a definition remember which word they belong to.
+        let loc = match status {
+            ProcessingBody(ref partial) => loc.clone().in_word(&partial.name),
+            _ => loc.clone(),
+        };
+        let mut atom = None;
         match status {
+            TopLevel | ProcessingBody(_) if is_block_word(tok) => {
+                let word = match tok {
+                    Tok::SymbolTok(sym) => sym,
+                    _ => unreachable!("Block words are always symbols"),
+                };
+                match block_word(&mut blocks, word, &loc) {
+                    Ok(completed) => atom = completed,
+                    Err((kind, message)) => error(kind, message, &loc),
+                }
+            },
             TopLevel => {
                 match tok {
                     Tok::LitTok(prim) =>
-                        ast.push(Ast::Simple(Atom::Lit(prim.clone()))),
+                        atom = Some(Atom::lit(prim.clone(), loc)),
                     Tok::SymbolTok(sym) =>
-                        ast.push(Ast::Simple(Atom::Symbol(sym.clone()))),
-                    Tok::Colon =>
-                        status = WaitingForName,
-                    bad =>
-                        panic!("Unexpected top-level token {:?}", bad),
+                        atom = Some(Atom::symbol(sym, loc)),
+                    Tok::Colon | Tok::DoubleColon if blocks.is_empty() =>
+                        status = WaitingForName(loc, *tok == Tok::DoubleColon),
+                    Tok::Colon | Tok::DoubleColon =>
+                        error(ParseErrorKind::UnexpectedToken,
+                              format!("unexpected {} inside a control structure", describe(tok)),
+                              &loc),
+                    Tok::SemiColon =>
+                        error(ParseErrorKind::UnexpectedToken,
+                              "unexpected \";\" outside of a word definition".to_string(),
+                              &loc),
+                    Tok::CommentTok(_) => (),
                 }
             },
-            WaitingForName => {
+            WaitingForName(ref colon_loc, prog_word) => {
                 match tok {
                     Tok::SymbolTok(name) =>
                         status = ProcessingBody(
                             PartialDefinition {
-                                name: name.clone(),
+                                name: Rc::from(name.as_str()),
                                 words: Vec::new(),
-                                finished: false }
+                                loc: colon_loc.clone(),
+                                finished: false,
+                                prog_word }
                         ),
-                    bad =>
-                        panic!("Expected name of word after :, got {:?}", bad)
+                    Tok::CommentTok(_) => (),
+                    bad => {
+                        error(ParseErrorKind::MissingWordName,
+                              format!("expected the name of a word after {}, got {}",
+                                      if prog_word { "\"::\"" } else { "\":\"" },
+                                      describe(bad)),
+                              &loc);
+                        status = if *bad == Tok::SemiColon { TopLevel } else { SkippingBody };
+                    },
                 }
             },
             ProcessingBody(ref mut partial) => {
                 match tok {
                     Tok::LitTok(prim) =>
-                        partial.words.push(Atom::Lit(prim.clone())),
+                        atom = Some(Atom::lit(prim.clone(), loc)),
                     Tok::SymbolTok(sym) =>
-                        partial.words.push(Atom::Symbol(sym.clone())),
-                    Tok::SemiColon =>
-                        partial.finished = true,
-                    bad =>
-                        panic!("Unexpected top-level token {:?}", bad),
+                        atom = Some(Atom::symbol(sym, loc)),
+                    Tok::SemiColon => {
+                        if let Some(block) = blocks.first() {
+                            error(ParseErrorKind::UnexpectedToken,
+                                  format!("unexpected \";\" before the {} closing {:?}",
+                                          block.kind.closer(), block.kind.opener()),
+                                  &loc);
+                            blocks.clear();
+                        }
+                        partial.loc.span.end = loc.span.end;
+                        partial.finished = true
+                    },
+                    Tok::Colon | Tok::DoubleColon =>
+                        error(ParseErrorKind::UnexpectedToken,
+                              format!("unexpected {} inside a word definition", describe(tok)),
+                              &loc),
+                    Tok::CommentTok(_) => (),
                 }
-            }
+            },
+            SkippingBody => {
+                if *tok == Tok::SemiColon {
+                    status = TopLevel;
+                }
+            },
         };
 
+        if let Some(atom) = atom {
+            match (blocks.last_mut(), &mut status) {
+                (Some(block), _) => block.current.push(atom),
+                (None, ProcessingBody(partial)) => partial.words.push(atom),
+                (None, _) => ast.push(Ast::Simple(atom)),
+            }
+        }
+
         // Make sure you understand why this needs to be in a separate block from
         // the ProcessingBody section above:
         //
@@ -79,54 +299,422 @@ pub fn tokens_to_ast(tokens: &Vec<Tok>) -> Vec<Ast> {
         // we're not allowed to take ownership of `status` when there's a mutable
         // reference `partial` still out on it. In theory, calling drop(partial) allow
         // it but I wasn't seeing how to make the borrow checker happy.
-        match status {
-            ProcessingBody( PartialDefinition { name, words, finished: true} ) => {
-                status = DefinitionStatus::TopLevel;
-                ast.push(Ast::WordDef(name, words))
-            },
-            _ => (),
+        if let ProcessingBody( PartialDefinition { name, words, loc, finished: true, prog_word } ) = status {
+            status = DefinitionStatus::TopLevel;
+            if prog_word {
+                ast.push(Ast::ProgWordDef(name.to_string(), words, loc))
+            } else {
+                ast.push(Ast::WordDef(name.to_string(), words, loc))
+            }
         };
     };
 
-    ast
+    match status {
+        WaitingForName(ref loc, _) | ProcessingBody(PartialDefinition { ref loc, .. }) =>
+            error(ParseErrorKind::UnterminatedDefinition,
+                  "word definition is missing its closing \";\"".to_string(),
+                  loc),
+        TopLevel => {
+            if let Some(block) = blocks.first() {
+                error(ParseErrorKind::UnterminatedBlock,
+                      format!("{:?} is missing its closing {}",
+                              block.kind.opener(), block.kind.closer()),
+                      &block.loc);
+            }
+        },
+        SkippingBody => (),
+    };
+
+    if errors.is_empty() {
+        Ok(ast)
+    } else {
+        Err(errors)
+    }
 }
 
-#[test]
-fn test_parse_simple() {
-    let tokens = vec![
-        Tok::LitTok(Prim::Int(2)),
-        Tok::SymbolTok("DUP".to_string()),
-    ];
-    let expected = vec![
-        Ast::Simple(Atom::Lit(Prim::Int(2))),
-        Ast::Simple(Atom::Symbol("DUP".to_string()))
-    ];
-    let actual = tokens_to_ast(&tokens);
-    assert_eq!(expected, actual);
+// How to refer to a token in an error message.
+fn describe(tok: &Tok) -> String {
+    match tok {
+        Tok::LitTok(prim) => format!("literal {:?}", prim),
+        Tok::SymbolTok(sym) => format!("{:?}", sym),
+        Tok::SemiColon => "\";\"".to_string(),
+        Tok::Colon => "\":\"".to_string(),
+        Tok::DoubleColon => "\"::\"".to_string(),
+        Tok::CommentTok(text) => format!("comment {:?}", text),
+    }
 }
 
-#[test]
-fn test_parse_complex() {
-    let tokens = vec![
-        Tok::Colon,
-        Tok::SymbolTok("SQUARE".to_string()),
-        Tok::SymbolTok("DUP".to_string()),
-        Tok::SymbolTok("*".to_string()),
-        Tok::SemiColon,
-        Tok::LitTok(Prim::Int(2)),
-        Tok::SymbolTok("SQUARE".to_string()),
-    ];
-    let expected = vec![
-        Ast::WordDef(
-            "SQUARE".to_string(),
-            vec![
-                Atom::Symbol("DUP".to_string()),
-                Atom::Symbol("*".to_string()),
-            ]
-        ),
-        Ast::Simple(Atom::Lit(Prim::Int(2))),
-        Ast::Simple(Atom::Symbol("SQUARE".to_string()))
-    ];
-    let actual = tokens_to_ast(&tokens);
-    assert_eq!(expected, actual);
-}
\ No newline at end of file
+#[cfg(test)]
+mod test_parse {
+    use super::*;
+    use super::super::lex::Lexer;
+    use super::super::loc::Span;
+
+    // The parser doesn't look at locations except to report errors, so most
+    // tests can just use empty ones.
+    fn without_locs(tokens: Vec<Tok>) -> Vec<Token> {
+        tokens.into_iter()
+            .map(|tok| Token { tok, loc: Loc::default() })
+            .collect()
+    }
+
+    fn parse_str(source: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
+        let tokens = Lexer::new().tokenize(source, None).unwrap();
+        tokens_to_ast(&tokens, source)
+    }
+
+    // Parse, then drop the locations, for tests that only check structure.
+    fn parse_plain(source: &str) -> Result<Vec<Ast>, Vec<ParseError>> {
+        parse_str(source).map(|ast| ast.into_iter().map(Ast::without_locs).collect())
+    }
+
+    fn parse_errors(source: &str) -> Vec<ParseError> {
+        parse_str(source).unwrap_err()
+    }
+
+    fn lit(prim: Prim) -> Atom {
+        Atom::lit(prim, Loc::default())
+    }
+
+    fn sym(name: &str) -> Atom {
+        Atom::symbol(name, Loc::default())
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        let tokens = vec![
+            Tok::LitTok(Prim::Int(2)),
+            Tok::SymbolTok("DUP".to_string()),
+        ];
+        let expected = vec![
+            Ast::Simple(lit(Prim::Int(2))),
+            Ast::Simple(sym("DUP"))
+        ];
+        let actual: Vec<Ast> = tokens_to_ast(&without_locs(tokens), "").unwrap()
+            .into_iter().map(Ast::without_locs).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_parse_complex() {
+        let tokens = vec![
+            Tok::Colon,
+            Tok::SymbolTok("SQUARE".to_string()),
+            Tok::SymbolTok("DUP".to_string()),
+            Tok::SymbolTok("*".to_string()),
+            Tok::SemiColon,
+            Tok::LitTok(Prim::Int(2)),
+            Tok::SymbolTok("SQUARE".to_string()),
+        ];
+        let expected = vec![
+            Ast::WordDef(
+                "SQUARE".to_string(),
+                vec![
+                    sym("DUP"),
+                    sym("*"),
+                ],
+                Loc::default(),
+            ),
+            Ast::Simple(lit(Prim::Int(2))),
+            Ast::Simple(sym("SQUARE"))
+        ];
+        let actual: Vec<Ast> = tokens_to_ast(&without_locs(tokens), "").unwrap()
+            .into_iter().map(Ast::without_locs).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_parse_stray_semicolon() {
+        let errors = parse_errors("1 2 ; +");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnexpectedToken);
+        assert_eq!(errors[0].span, Span::new(4, 5));
+        assert_eq!(errors[0].column, 5);
+    }
+
+    #[test]
+    fn test_parse_missing_name() {
+        // The body of the broken definition is skipped, so we only get one error.
+        let errors = parse_errors("1\n: 3 dup * ; 4");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::MissingWordName);
+        assert_eq!((errors[0].line, errors[0].column), (2, 3));
+        assert_eq!(errors[0].source_line, ": 3 dup * ; 4");
+    }
+
+    #[test]
+    fn test_parse_reports_all_errors() {
+        let errors = parse_errors(": a : b ; ;");
+        let kinds: Vec<ParseErrorKind> = errors.iter().map(|err| err.kind).collect();
+        assert_eq!(kinds, vec![ParseErrorKind::UnexpectedToken, ParseErrorKind::UnexpectedToken]);
+    }
+
+    #[test]
+    fn test_parse_locations() {
+        let ast = parse_str("1\n: sq\n  dup * ;\nsq").unwrap();
+        let positions: Vec<(usize, usize)> = ast.iter()
+            .map(|node| (node.loc().line, node.loc().column))
+            .collect();
+        assert_eq!(positions, vec![(1, 1), (2, 1), (4, 1)]);
+        match &ast[1] {
+            Ast::WordDef(_, body, loc) => {
+                assert_eq!(loc.span, Span::new(2, 16));
+                let word = Some(Rc::from("sq"));
+                assert_eq!(body[1].loc.word, word);
+                assert_eq!((body[1].loc.line, body[1].loc.column), (3, 7));
+            },
+            other => panic!("Expected a word definition, got {:?}", other),
+        }
+        assert_eq!(ast[2].loc().word, None);
+    }
+
+    #[test]
+    fn test_parse_unterminated_definition() {
+        let errors = parse_errors("1 2 : sq dup");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedDefinition);
+        assert_eq!(errors[0].span.start, 4);
+        let errors = parse_errors("1 2 :");
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedDefinition);
+    }
+
+    #[test]
+    fn test_parse_prog_word() {
+        let ast = parse_plain(":: twice quote-next dup [ call ] dip call ; twice 1+").unwrap();
+        assert_eq!(ast, vec![
+            Ast::ProgWordDef(
+                "twice".to_string(),
+                vec![
+                    sym("quote-next"), sym("dup"), lit(Prim::Quot(vec![sym("call")])),
+                    sym("dip"), sym("call"),
+                ],
+                Loc::default(),
+            ),
+            Ast::Simple(sym("twice")),
+            Ast::Simple(sym("1+")),
+        ]);
+        let errors = parse_errors(":: ;");
+        assert_eq!(errors[0].kind, ParseErrorKind::MissingWordName);
+        assert_eq!(errors[0].message, "expected the name of a word after \"::\", got \";\"");
+        let errors = parse_errors(": a :: b ; ;");
+        assert_eq!(errors[0].message, "unexpected \"::\" inside a word definition");
+        let errors = parse_errors("1 2 :: sq");
+        assert_eq!(errors[0].kind, ParseErrorKind::UnterminatedDefinition);
+    }
+
+    fn if_atom(then_branch: Vec<Atom>, else_branch: Vec<Atom>) -> Atom {
+        Atom::new(AtomKind::If(then_branch, else_branch), Loc::default())
+    }
+
+    #[test]
+    fn test_parse_if() {
+        assert_eq!(
+            parse_plain("1 if 2 then 3"),
+            Ok(vec![
+                Ast::Simple(lit(Prim::Int(1))),
+                Ast::Simple(if_atom(vec![lit(Prim::Int(2))], vec![])),
+                Ast::Simple(lit(Prim::Int(3))),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_if_else() {
+        assert_eq!(
+            parse_plain(": sign dup 0 < if drop -1 else 0 > if 1 else 0 then then ;"),
+            Ok(vec![
+                Ast::WordDef(
+                    "sign".to_string(),
+                    vec![
+                        sym("dup"),
+                        lit(Prim::Int(0)),
+                        sym("<"),
+                        if_atom(
+                            vec![sym("drop"), lit(Prim::Int(-1))],
+                            vec![
+                                lit(Prim::Int(0)),
+                                sym(">"),
+                                if_atom(vec![lit(Prim::Int(1))], vec![lit(Prim::Int(0))]),
+                            ],
+                        ),
+                    ],
+                    Loc::default(),
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_if_locations() {
+        let ast = parse_str(": f\n  if dup then ;").unwrap();
+        match &ast[0] {
+            Ast::WordDef(_, body, _) => {
+                assert_eq!((body[0].loc.line, body[0].loc.column), (2, 3));
+                match &body[0].kind {
+                    AtomKind::If(then_branch, _) =>
+                        assert_eq!(then_branch[0].loc.word, Some(Rc::from("f"))),
+                    other => panic!("Expected an if, got {:?}", other),
+                }
+            },
+            other => panic!("Expected a word definition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_if_errors() {
+        let kinds = |source: &str| -> Vec<ParseErrorKind> {
+            parse_errors(source).iter().map(|err| err.kind).collect()
+        };
+        assert_eq!(kinds("1 then"), vec![ParseErrorKind::UnexpectedToken]);
+        assert_eq!(kinds("1 if 2 else 3 else 4 then"), vec![ParseErrorKind::UnexpectedToken]);
+        assert_eq!(kinds(": f if 1 ; 2"), vec![ParseErrorKind::UnexpectedToken]);
+        assert_eq!(kinds("1 if 2 else"), vec![ParseErrorKind::UnterminatedBlock]);
+        assert_eq!(kinds(": f if 1"), vec![ParseErrorKind::UnterminatedDefinition]);
+    }
+
+    #[test]
+    fn test_parse_quotations() {
+        let quot = |atoms: Vec<Atom>| lit(Prim::Quot(atoms));
+        assert_eq!(
+            parse_plain("[ dup * ] [ ] [ 1 [ 2 ] if 3 then ] call"),
+            Ok(vec![
+                Ast::Simple(quot(vec![sym("dup"), sym("*")])),
+                Ast::Simple(quot(vec![])),
+                Ast::Simple(quot(vec![
+                    lit(Prim::Int(1)),
+                    quot(vec![lit(Prim::Int(2))]),
+                    if_atom(vec![lit(Prim::Int(3))], vec![]),
+                ])),
+                Ast::Simple(sym("call")),
+            ])
+        );
+        assert_eq!(
+            parse_plain(": sq [ dup * ] call ;"),
+            Ok(vec![Ast::WordDef(
+                "sq".to_string(),
+                vec![quot(vec![sym("dup"), sym("*")]), sym("call")],
+                Loc::default(),
+            )])
+        );
+        let kinds = |source: &str| -> Vec<ParseErrorKind> {
+            parse_errors(source).iter().map(|err| err.kind).collect()
+        };
+        assert_eq!(kinds("1 ]"), vec![ParseErrorKind::UnexpectedToken]);
+        assert_eq!(kinds("[ 1 if ] then ]"), vec![ParseErrorKind::UnexpectedToken]);
+        assert_eq!(kinds("[ : f ; ]"), vec![ParseErrorKind::UnexpectedToken; 2]);
+        assert_eq!(kinds("[ 1 [ 2 ]"), vec![ParseErrorKind::UnterminatedBlock]);
+        assert_eq!(parse_errors("1 ]")[0].message, "\"]\" without a matching \"[\"");
+    }
+
+    #[test]
+    fn test_display_quotations() {
+        // A quotation displays as the code it was parsed from (give or
+        // take whitespace and comments).
+        let source = "[ 1 2/3 -0.5 \"a\" [ ] [ dup * ] swap if drop else 0 do i +loop then \
+                      begin 1 until begin dup while 1 - repeat ]";
+        match &parse_str(source).unwrap()[..] {
+            [Ast::Simple(atom)] => {
+                let words: Vec<&str> = source.split_whitespace().collect();
+                assert_eq!(atom.to_string(), words.join(" "));
+            },
+            other => panic!("Expected one quotation, got {:?}", other),
+        }
+        assert_eq!(Prim::Float(1.0).to_string(), "1.0");
+        assert_eq!(Prim::Float(f64::NAN).to_string(), "nan");
+        assert_eq!(Prim::Float(f64::NEG_INFINITY).to_string(), "-inf");
+    }
+
+    #[test]
+    fn test_parse_combinator_names() {
+        let quot = |atoms: Vec<Atom>| lit(Prim::Quot(atoms));
+        // Block words mean the same thing wherever they are, including
+        // straight after a quotation...
+        assert_eq!(
+            parse_plain("3 0 do [ 1 ] loop"),
+            Ok(vec![
+                Ast::Simple(lit(Prim::Int(3))),
+                Ast::Simple(lit(Prim::Int(0))),
+                Ast::Simple(Atom::new(
+                    AtomKind::Do { body: vec![quot(vec![lit(Prim::Int(1))])], conditional: false, plus: false },
+                    Loc::default(),
+                )),
+            ])
+        );
+        // ... so the combinators with the same names have to be called by
+        // their qualified names.
+        assert_eq!(
+            parse_plain(": two [ 10 ] [ 20 ] ; 1 two combinators:if ."),
+            Ok(vec![
+                Ast::WordDef(
+                    "two".to_string(),
+                    vec![quot(vec![lit(Prim::Int(10))]), quot(vec![lit(Prim::Int(20))])],
+                    Loc::default(),
+                ),
+                Ast::Simple(lit(Prim::Int(1))),
+                Ast::Simple(sym("two")),
+                Ast::Simple(sym("combinators:if")),
+                Ast::Simple(sym(".")),
+            ])
+        );
+        let kinds = |source: &str| -> Vec<ParseErrorKind> {
+            parse_errors(source).iter().map(|err| err.kind).collect()
+        };
+        assert_eq!(kinds("1 [ 2 ] [ 3 ] if"), vec![ParseErrorKind::UnterminatedBlock]);
+    }
+
+    #[test]
+    fn test_parse_loops() {
+        assert_eq!(
+            parse_plain("10 0 do i loop 0 5 ?do i -1 +loop begin 1 until begin dup while 1 - repeat"),
+            Ok(vec![
+                Ast::Simple(lit(Prim::Int(10))),
+                Ast::Simple(lit(Prim::Int(0))),
+                Ast::Simple(Atom::new(
+                    AtomKind::Do { body: vec![sym("i")], conditional: false, plus: false },
+                    Loc::default(),
+                )),
+                Ast::Simple(lit(Prim::Int(0))),
+                Ast::Simple(lit(Prim::Int(5))),
+                Ast::Simple(Atom::new(
+                    AtomKind::Do {
+                        body: vec![sym("i"), lit(Prim::Int(-1))],
+                        conditional: true,
+                        plus: true,
+                    },
+                    Loc::default(),
+                )),
+                Ast::Simple(Atom::new(
+                    AtomKind::BeginUntil(vec![lit(Prim::Int(1))]),
+                    Loc::default(),
+                )),
+                Ast::Simple(Atom::new(
+                    AtomKind::BeginWhile(
+                        vec![sym("dup")],
+                        vec![lit(Prim::Int(1)), sym("-")],
+                    ),
+                    Loc::default(),
+                )),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_loop_errors() {
+        let messages = |source: &str| -> Vec<String> {
+            parse_errors(source).iter().map(|err| err.message.clone()).collect()
+        };
+        assert_eq!(messages("1 loop"), vec!["\"loop\" without a matching \"do\""]);
+        assert_eq!(messages("do if loop then"), vec![
+            "unexpected \"loop\" inside \"if\"",
+            "\"do\" is missing its closing \"loop\" or \"+loop\"",
+        ]);
+        assert_eq!(messages("begin 1 while 2 until"), vec![
+            "unexpected \"until\" inside \"begin\"",
+            "\"begin\" is missing its closing \"until\" or \"repeat\"",
+        ]);
+        assert_eq!(messages("begin 1 repeat"), vec![
+            "unexpected \"repeat\" inside \"begin\"",
+            "\"begin\" is missing its closing \"until\" or \"repeat\"",
+        ]);
+    }
+}
@@ -1,25 +1,209 @@
-#[derive(Debug,PartialEq,Eq,Clone)]
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+use super::loc::Loc;
+
+#[derive(Debug,PartialEq,Clone)]
 pub enum Tok {
     LitTok(Prim),
     SemiColon,
     Colon,
-    SymbolTok(String)
+    // `::`, which starts a definition of a program word.
+    DoubleColon,
+    SymbolTok(String),
+    // A comment, including its delimiters. The lexer only produces these
+    // when asked to keep comments, e.g. for tools that reformat source.
+    CommentTok(String),
 }
 
-#[derive(Debug,PartialEq,Eq,Clone)]
-pub enum Atom {
+// A token, plus where it came from in the input.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Token {
+    pub tok: Tok,
+    pub loc: Loc,
+}
+
+#[derive(Debug,PartialEq,Clone)]
+pub enum AtomKind {
     Lit(Prim),
     Symbol(String),
+    // `if <then-branch> else <else-branch> then`; the else branch may be empty.
+    If(Vec<Atom>, Vec<Atom>),
+    // `do <body> loop`, or `+loop` if `plus` is set. `conditional` is set
+    // for `?do`, which skips the loop when the start equals the limit.
+    Do { body: Vec<Atom>, conditional: bool, plus: bool },
+    // `begin <body> until`
+    BeginUntil(Vec<Atom>),
+    // `begin <condition> while <body> repeat`
+    BeginWhile(Vec<Atom>, Vec<Atom>),
+}
+
+// The unit of execution. The location travels with the atom, including
+// when the vm copies a word body onto the program stack.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Atom {
+    pub kind: AtomKind,
+    pub loc: Loc,
+}
+
+impl Atom {
+
+    pub fn new(kind: AtomKind, loc: Loc) -> Self {
+        Atom { kind, loc }
+    }
+
+    pub fn lit(prim: Prim, loc: Loc) -> Self {
+        Atom::new(AtomKind::Lit(prim), loc)
+    }
+
+    pub fn symbol(name: &str, loc: Loc) -> Self {
+        Atom::new(AtomKind::Symbol(name.to_string()), loc)
+    }
+
+    // A copy with every location (including those inside branches, loop
+    // bodies and quotations) reset to the default, for comparing
+    // structure alone.
+    pub fn without_locs(self) -> Self {
+        let strip = |atoms: Vec<Atom>| atoms.into_iter().map(Atom::without_locs).collect();
+        let kind = match self.kind {
+            AtomKind::Lit(prim) => AtomKind::Lit(prim.without_locs()),
+            AtomKind::Symbol(name) => AtomKind::Symbol(name),
+            AtomKind::If(then_branch, else_branch) =>
+                AtomKind::If(strip(then_branch), strip(else_branch)),
+            AtomKind::Do { body, conditional, plus } =>
+                AtomKind::Do { body: strip(body), conditional, plus },
+            AtomKind::BeginUntil(body) => AtomKind::BeginUntil(strip(body)),
+            AtomKind::BeginWhile(condition, body) =>
+                AtomKind::BeginWhile(strip(condition), strip(body)),
+        };
+        Atom::new(kind, Loc::default())
+    }
 }
 
-#[derive(Debug,PartialEq,Eq,Clone)]
+#[derive(Debug,PartialEq,Clone)]
 pub enum Ast {
     Simple(Atom),
-    WordDef(String, Vec<Atom>),
+    // A word definition, located from its `:` through its `;`
+    WordDef(String, Vec<Atom>, Loc),
+    // A `::` definition of a program word, which can see and change the
+    // program that follows its call (e.g. to skip the next word).
+    ProgWordDef(String, Vec<Atom>, Loc),
 }
 
-#[derive(Debug,PartialEq,Eq,Clone)]
+impl Ast {
+    pub fn loc(&self) -> &Loc {
+        match self {
+            Ast::Simple(atom) => &atom.loc,
+            Ast::WordDef(_, _, loc) | Ast::ProgWordDef(_, _, loc) => loc,
+        }
+    }
+
+    // Like `Atom::without_locs`.
+    pub fn without_locs(self) -> Self {
+        let strip = |atoms: Vec<Atom>| atoms.into_iter().map(Atom::without_locs).collect();
+        match self {
+            Ast::Simple(atom) => Ast::Simple(atom.without_locs()),
+            Ast::WordDef(name, body, _) => Ast::WordDef(name, strip(body), Loc::default()),
+            Ast::ProgWordDef(name, body, _) => Ast::ProgWordDef(name, strip(body), Loc::default()),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq,Clone)]
 pub enum Prim {
     Str(String), // a fully-owned string
     Int(i64),
-}
\ No newline at end of file
+    // An int too big for an i64. The vm only produces these (there's no
+    // literal syntax) when its overflow policy is to promote, and turns
+    // them back into a Prim::Int as soon as they fit again.
+    BigInt(BigInt),
+    // An exact fraction, e.g. from a `1/3` literal. It's always in lowest
+    // terms and never a whole number (those are ints instead).
+    Ratio(BigRational),
+    Float(f64),
+    // Only produced by the vm (e.g. by `=` when it's set to use bool
+    // flags); there's no literal syntax for it.
+    Bool(bool),
+    // A quotation, `[ ... ]`: a piece of program that sits on the stack
+    // as data until a word like `call` runs it.
+    Quot(Vec<Atom>),
+}
+
+impl Prim {
+
+    // Like `Atom::without_locs`; only quotations hold locations.
+    pub fn without_locs(self) -> Self {
+        match self {
+            Prim::Quot(body) => Prim::Quot(body.into_iter().map(Atom::without_locs).collect()),
+            other => other,
+        }
+    }
+}
+
+// Values are shown the way they'd be written, e.g. `2/3` or `[ dup * ]`,
+// so that `.` prints something that can be read back in.
+impl fmt::Display for Prim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Prim::Str(text) => write!(f, "{:?}", text),
+            Prim::Int(n) => write!(f, "{}", n),
+            Prim::BigInt(n) => write!(f, "{}", n),
+            Prim::Ratio(r) => write!(f, "{}/{}", r.numer(), r.denom()),
+            // Debug keeps the `.0` of a whole float, so it doesn't read
+            // back as an int.
+            Prim::Float(x) if x.is_nan() => write!(f, "nan"),
+            Prim::Float(x) => write!(f, "{:?}", x),
+            Prim::Bool(b) => write!(f, "{}", b),
+            Prim::Quot(body) => {
+                write!(f, "[ ")?;
+                write_atoms(f, body)?;
+                write!(f, "]")
+            },
+        }
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            AtomKind::Lit(prim) => write!(f, "{}", prim),
+            AtomKind::Symbol(name) => write!(f, "{}", name),
+            AtomKind::If(then_branch, else_branch) => {
+                write!(f, "if ")?;
+                write_atoms(f, then_branch)?;
+                if !else_branch.is_empty() {
+                    write!(f, "else ")?;
+                    write_atoms(f, else_branch)?;
+                }
+                write!(f, "then")
+            },
+            AtomKind::Do { body, conditional, plus } => {
+                write!(f, "{} ", if *conditional { "?do" } else { "do" })?;
+                write_atoms(f, body)?;
+                write!(f, "{}", if *plus { "+loop" } else { "loop" })
+            },
+            AtomKind::BeginUntil(body) => {
+                write!(f, "begin ")?;
+                write_atoms(f, body)?;
+                write!(f, "until")
+            },
+            AtomKind::BeginWhile(condition, body) => {
+                write!(f, "begin ")?;
+                write_atoms(f, condition)?;
+                write!(f, "while ")?;
+                write_atoms(f, body)?;
+                write!(f, "repeat")
+            },
+        }
+    }
+}
+
+// Write each atom followed by a space.
+fn write_atoms(f: &mut fmt::Formatter, atoms: &[Atom]) -> fmt::Result {
+    for atom in atoms {
+        write!(f, "{} ", atom)?;
+    }
+    Ok(())
+}
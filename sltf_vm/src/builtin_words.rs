@@ -1,86 +1,1206 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::rc::Rc;
 
-use either::{Either, Left, Right};
+use either::Left;
 
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+
+use super::Instr;
+use super::IntDivision;
+use super::OverflowPolicy;
 use super::Stack;
+use super::Vm;
 use super::WordMap;
 use super::Prim;
+use super::VmError;
 
 
 pub fn initial_word_map() -> WordMap {
     let mut word_map: WordMap = HashMap::new();
-    word_map.insert("drop".to_string(), Left(bi_drop));
-    word_map.insert("dup".to_string(), Left(bi_dup));
-    word_map.insert("dup2".to_string(), Left(bi_dup2));
-    word_map.insert("swap".to_string(), Left(bi_swap));
-    word_map.insert("+".to_string(), Left(bi_add));
-    word_map.insert("*".to_string(), Left(bi_mult));
-    word_map.insert(".".to_string(), Left(bi_show_stack));
+    word_map.insert("drop".to_string(), Left(Rc::new(bi_drop)));
+    word_map.insert("dup".to_string(), Left(Rc::new(bi_dup)));
+    word_map.insert("dup2".to_string(), Left(Rc::new(bi_dup2)));
+    word_map.insert("swap".to_string(), Left(Rc::new(bi_swap)));
+    word_map.insert("over".to_string(), Left(Rc::new(bi_over)));
+    word_map.insert("rot".to_string(), Left(Rc::new(bi_rot)));
+    word_map.insert("-rot".to_string(), Left(Rc::new(bi_minus_rot)));
+    word_map.insert("nip".to_string(), Left(Rc::new(bi_nip)));
+    word_map.insert("tuck".to_string(), Left(Rc::new(bi_tuck)));
+    word_map.insert("pick".to_string(), Left(Rc::new(bi_pick)));
+    word_map.insert("roll".to_string(), Left(Rc::new(bi_roll)));
+    word_map.insert("2drop".to_string(), Left(Rc::new(bi_two_drop)));
+    word_map.insert("2swap".to_string(), Left(Rc::new(bi_two_swap)));
+    word_map.insert("2over".to_string(), Left(Rc::new(bi_two_over)));
+    word_map.insert("?dup".to_string(), Left(Rc::new(bi_question_dup)));
+    word_map.insert("depth".to_string(), Left(Rc::new(bi_depth)));
+    word_map.insert("+".to_string(), Left(Rc::new(bi_add)));
+    word_map.insert("*".to_string(), Left(Rc::new(bi_mult)));
+    word_map.insert("-".to_string(), Left(Rc::new(bi_sub)));
+    word_map.insert("/".to_string(), Left(Rc::new(bi_div)));
+    word_map.insert("mod".to_string(), Left(Rc::new(bi_mod)));
+    word_map.insert("/mod".to_string(), Left(Rc::new(bi_div_mod)));
+    word_map.insert("fm/mod".to_string(), Left(Rc::new(bi_fm_mod)));
+    word_map.insert("sm/rem".to_string(), Left(Rc::new(bi_sm_rem)));
+    word_map.insert("*/".to_string(), Left(Rc::new(bi_mult_div)));
+    word_map.insert("negate".to_string(), Left(Rc::new(bi_negate)));
+    word_map.insert("abs".to_string(), Left(Rc::new(bi_abs)));
+    word_map.insert("min".to_string(), Left(Rc::new(bi_min)));
+    word_map.insert("max".to_string(), Left(Rc::new(bi_max)));
+    word_map.insert("1+".to_string(), Left(Rc::new(bi_one_plus)));
+    word_map.insert("1-".to_string(), Left(Rc::new(bi_one_minus)));
+    word_map.insert("2*".to_string(), Left(Rc::new(bi_two_mult)));
+    word_map.insert("2/".to_string(), Left(Rc::new(bi_two_div)));
+    word_map.insert("sqrt".to_string(), Left(Rc::new(bi_sqrt)));
+    word_map.insert("sin".to_string(), Left(Rc::new(bi_sin)));
+    word_map.insert("cos".to_string(), Left(Rc::new(bi_cos)));
+    word_map.insert("exp".to_string(), Left(Rc::new(bi_exp)));
+    word_map.insert("ln".to_string(), Left(Rc::new(bi_ln)));
+    word_map.insert("floor".to_string(), Left(Rc::new(bi_floor)));
+    word_map.insert("round".to_string(), Left(Rc::new(bi_round)));
+    word_map.insert("f.".to_string(), Left(Rc::new(bi_print_float)));
+    word_map.insert("s>f".to_string(), Left(Rc::new(bi_int_to_float)));
+    word_map.insert("f>s".to_string(), Left(Rc::new(bi_float_to_int)));
+    word_map.insert("ratio".to_string(), Left(Rc::new(bi_ratio)));
+    word_map.insert("numerator".to_string(), Left(Rc::new(bi_numerator)));
+    word_map.insert("denominator".to_string(), Left(Rc::new(bi_denominator)));
+    word_map.insert(">int".to_string(), Left(Rc::new(bi_to_int)));
+    word_map.insert(">float".to_string(), Left(Rc::new(bi_to_float)));
+    word_map.insert(".".to_string(), Left(Rc::new(bi_show_stack)));
+    word_map.insert("=".to_string(), Left(Rc::new(bi_eq)));
+    word_map.insert("<>".to_string(), Left(Rc::new(bi_ne)));
+    word_map.insert("<".to_string(), Left(Rc::new(bi_lt)));
+    word_map.insert(">".to_string(), Left(Rc::new(bi_gt)));
+    word_map.insert("<=".to_string(), Left(Rc::new(bi_le)));
+    word_map.insert(">=".to_string(), Left(Rc::new(bi_ge)));
+    word_map.insert("0=".to_string(), Left(Rc::new(bi_zero_eq)));
+    word_map.insert("0<".to_string(), Left(Rc::new(bi_zero_lt)));
+    word_map.insert("and".to_string(), Left(Rc::new(bi_and)));
+    word_map.insert("or".to_string(), Left(Rc::new(bi_or)));
+    word_map.insert("xor".to_string(), Left(Rc::new(bi_xor)));
+    word_map.insert("invert".to_string(), Left(Rc::new(bi_invert)));
+    word_map.insert("lshift".to_string(), Left(Rc::new(bi_lshift)));
+    word_map.insert("rshift".to_string(), Left(Rc::new(bi_rshift)));
+    word_map.insert("arshift".to_string(), Left(Rc::new(bi_arshift)));
+    word_map.insert("popcount".to_string(), Left(Rc::new(bi_popcount)));
+    word_map.insert("true".to_string(), Left(Rc::new(bi_true)));
+    word_map.insert("false".to_string(), Left(Rc::new(bi_false)));
+    word_map.insert("i".to_string(), Left(Rc::new(bi_i)));
+    word_map.insert("j".to_string(), Left(Rc::new(bi_j)));
+    word_map.insert("leave".to_string(), Left(Rc::new(bi_leave)));
+    word_map.insert(">r".to_string(), Left(Rc::new(bi_to_r)));
+    word_map.insert("r>".to_string(), Left(Rc::new(bi_r_from)));
+    word_map.insert("r@".to_string(), Left(Rc::new(bi_r_fetch)));
+    word_map.insert("2>r".to_string(), Left(Rc::new(bi_two_to_r)));
+    word_map.insert("2r>".to_string(), Left(Rc::new(bi_two_r_from)));
+    word_map.insert("rdrop".to_string(), Left(Rc::new(bi_rdrop)));
     word_map
 }
 
-fn bi_drop(stack: &mut Stack) {
+// Pop a flag for a conditional. Whatever the vm's flag style, both kinds
+// of flag are accepted: a bool, or a number of any kind, where anything
+// nonzero is true.
+pub fn pop_flag(stack: &mut Stack, word: &str) -> Result<bool, VmError> {
+    let value = peek_flag(stack, word)?;
     stack.pop();
+    Ok(value)
+}
+
+// Like `pop_flag`, but leave the flag on the stack.
+fn peek_flag(stack: &Stack, word: &str) -> Result<bool, VmError> {
+    require(stack, word, 1)?;
+    match &stack[stack.len() - 1] {
+        Prim::Int(n) => Ok(*n != 0),
+        // Big ints and ratios are never zero.
+        Prim::BigInt(_) | Prim::Ratio(_) => Ok(true),
+        Prim::Float(x) => Ok(*x != 0.0),
+        Prim::Bool(b) => Ok(*b),
+        bad => Err(type_mismatch(word, "flag", bad)),
+    }
+}
+
+// Check that `word` has at least `needed` values to work with. Builtins
+// call this before popping anything, so that a failed word leaves the
+// stack as it found it.
+pub fn require(stack: &Stack, word: &str, needed: usize) -> Result<(), VmError> {
+    if stack.len() < needed {
+        Err(VmError::StackUnderflow {
+            word: word.to_string(),
+            needed,
+            found: stack.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// Like `require`, but for the return stack.
+fn require_r(rstack: &Stack, word: &str, needed: usize) -> Result<(), VmError> {
+    if rstack.len() < needed {
+        Err(VmError::ReturnStackUnderflow {
+            word: word.to_string(),
+            needed,
+            found: rstack.len(),
+        })
+    } else {
+        Ok(())
+    }
 }
 
-fn bi_show_stack(stack: &mut Stack) {
-    for prim in stack {
-        print!(" {:?}", prim);
+pub fn type_mismatch(word: &str, expected: &'static str, found: &Prim) -> VmError {
+    VmError::TypeMismatch {
+        word: word.to_string(),
+        expected,
+        found: found.clone(),
     }
 }
 
-fn bi_dup(stack: &mut Stack) {
+// Pop the top value, which must be an int.
+pub fn pop_int(stack: &mut Stack, word: &str) -> Result<i64, VmError> {
+    require(stack, word, 1)?;
+    let value = match &stack[stack.len() - 1] {
+        Prim::Int(n) => *n,
+        bad => return Err(type_mismatch(word, "int", bad)),
+    };
+    stack.pop();
+    Ok(value)
+}
+
+// Pop the top two values, which must both be ints. The first element
+// of the result was deeper in the stack.
+pub fn pop_int_pair(stack: &mut Stack, word: &str) -> Result<(i64, i64), VmError> {
+    require(stack, word, 2)?;
+    let n = stack.len();
+    let pair = match (&stack[n - 2], &stack[n - 1]) {
+        (Prim::Int(n1), Prim::Int(n0)) => (*n1, *n0),
+        (Prim::Int(_), bad) | (bad, _) => return Err(type_mismatch(word, "int", bad)),
+    };
+    stack.truncate(n - 2);
+    Ok(pair)
+}
+
+fn bi_drop(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "drop", 1)?;
+    stack.pop();
+    Ok(())
+}
+
+fn bi_show_stack(vm: &mut Vm) -> Result<(), VmError> {
+    let text: String = vm.stack.iter().map(|prim| format!(" {}", prim)).collect();
+    vm.write_output(".", &text)
+}
+
+fn bi_dup(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "dup", 1)?;
     // Note: the borrow checker is smart enough to know that if there's
     // a clone() call at the end of a line, we have not created a reference
     // to `stack`. If you try to put the clones inside the push statements,
     // it will complain because stack cannot be modified when i0 refers
     // to data in the stack.
-    let i0 = stack.last().expect("Called dup on empty stack").clone();
+    let i0 = stack[stack.len() - 1].clone();
     stack.push(i0);
+    Ok(())
 }
 
-fn bi_dup2(stack: &mut Stack) {
-    if (stack.len() < 2) {
-        panic!("Called dup on stack with fewer than two elements")
-    }
+fn bi_dup2(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "dup2", 2)?;
     let i0 = stack[stack.len() - 1].clone();
     let i1 = stack[stack.len() - 2].clone();
     stack.push(i1);
     stack.push(i0);
+    Ok(())
 }
 
-fn bi_swap(stack: &mut Stack) {
-    // Note that no clones are needed here because, unlike indexing or
-    // using last(), popping from the stack does *not* create a borrow of
-    // something in the stack
-    let i0 = stack.pop().expect("Called swap on empty stack");
-    let i1 = stack.pop().expect("Called swap on stack with one element");
-    stack.push(i0);
+fn bi_swap(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "swap", 2)?;
+    let n = stack.len();
+    stack.swap(n - 1, n - 2);
+    Ok(())
+}
+
+fn bi_over(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "over", 2)?;
+    let i1 = stack[stack.len() - 2].clone();
     stack.push(i1);
+    Ok(())
+}
+
+// ( x2 x1 x0 -- x1 x0 x2 )
+fn bi_rot(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "rot", 3)?;
+    let n = stack.len();
+    stack[n - 3..].rotate_left(1);
+    Ok(())
+}
+
+// ( x2 x1 x0 -- x0 x2 x1 )
+fn bi_minus_rot(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "-rot", 3)?;
+    let n = stack.len();
+    stack[n - 3..].rotate_right(1);
+    Ok(())
+}
+
+fn bi_nip(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "nip", 2)?;
+    let n = stack.len();
+    stack.remove(n - 2);
+    Ok(())
+}
+
+// ( x1 x0 -- x0 x1 x0 )
+fn bi_tuck(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "tuck", 2)?;
+    let n = stack.len();
+    let i0 = stack[n - 1].clone();
+    stack.insert(n - 2, i0);
+    Ok(())
+}
+
+// Pop the index `u` used by `pick` and `roll`, checking that there are
+// at least u + 1 values under it. On an error the index stays put.
+fn pop_index(stack: &mut Stack, word: &str) -> Result<usize, VmError> {
+    require(stack, word, 1)?;
+    let u = match &stack[stack.len() - 1] {
+        Prim::Int(n) if *n >= 0 => *n as usize,
+        bad => return Err(type_mismatch(word, "non-negative int", bad)),
+    };
+    require(stack, word, u.saturating_add(2))?;
+    stack.pop();
+    Ok(u)
+}
+
+// ( xu ... x0 u -- xu ... x0 xu )
+fn bi_pick(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let u = pop_index(stack, "pick")?;
+    let picked = stack[stack.len() - 1 - u].clone();
+    stack.push(picked);
+    Ok(())
+}
+
+// ( xu xu-1 ... x0 u -- xu-1 ... x0 xu )
+fn bi_roll(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let u = pop_index(stack, "roll")?;
+    let n = stack.len();
+    stack[n - 1 - u..].rotate_left(1);
+    Ok(())
+}
+
+fn bi_two_drop(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "2drop", 2)?;
+    let n = stack.len();
+    stack.truncate(n - 2);
+    Ok(())
+}
+
+// ( x3 x2 x1 x0 -- x1 x0 x3 x2 )
+fn bi_two_swap(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "2swap", 4)?;
+    let n = stack.len();
+    stack[n - 4..].rotate_left(2);
+    Ok(())
+}
+
+// ( x3 x2 x1 x0 -- x3 x2 x1 x0 x3 x2 )
+fn bi_two_over(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "2over", 4)?;
+    let n = stack.len();
+    let i3 = stack[n - 4].clone();
+    let i2 = stack[n - 3].clone();
+    stack.push(i3);
+    stack.push(i2);
+    Ok(())
+}
+
+// Duplicate the top value if it's a true flag, by the same rule as the
+// conditionals: any nonzero number, or `true`.
+fn bi_question_dup(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    if peek_flag(stack, "?dup")? {
+        let top = stack[stack.len() - 1].clone();
+        stack.push(top);
+    }
+    Ok(())
+}
+
+fn bi_depth(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let depth = stack.len() as i64;
+    stack.push(Prim::Int(depth));
+    Ok(())
+}
+
+
+// A number from the stack.
+enum Num {
+    Int(i64),
+    Big(BigInt),
+    Ratio(BigRational),
+    Float(f64),
+}
+
+impl Num {
+    fn from_prim(prim: &Prim) -> Option<Num> {
+        match prim {
+            Prim::Int(n) => Some(Num::Int(*n)),
+            Prim::BigInt(b) => Some(Num::Big(b.clone())),
+            Prim::Ratio(r) => Some(Num::Ratio(r.clone())),
+            Prim::Float(x) => Some(Num::Float(*x)),
+            _ => None,
+        }
+    }
+
+    fn to_float(&self) -> f64 {
+        match self {
+            Num::Int(n) => *n as f64,
+            Num::Big(b) => b.to_f64().unwrap_or(f64::NAN),
+            Num::Ratio(r) => r.to_f64().unwrap_or(f64::NAN),
+            Num::Float(x) => *x,
+        }
+    }
+
+    fn into_big(self) -> BigInt {
+        match self {
+            Num::Int(n) => BigInt::from(n),
+            Num::Big(b) => b,
+            Num::Ratio(_) | Num::Float(_) =>
+                unreachable!("Only ints are promoted to big ints"),
+        }
+    }
+
+    fn into_ratio(self) -> BigRational {
+        match self {
+            Num::Ratio(r) => r,
+            Num::Float(_) => unreachable!("Floats are never promoted to ratios"),
+            int => BigRational::from_integer(int.into_big()),
+        }
+    }
+}
+
+// Two numbers from the stack, promoted to the same representation: if
+// either one is a float they both are, and otherwise if either one is a
+// ratio they both are, and so on for big ints.
+enum Nums {
+    Ints(i64, i64),
+    Bigs(BigInt, BigInt),
+    Ratios(BigRational, BigRational),
+    Floats(f64, f64),
 }
 
+impl Nums {
+    fn new(n1: Num, n0: Num) -> Nums {
+        match (n1, n0) {
+            (Num::Int(n1), Num::Int(n0)) => Nums::Ints(n1, n0),
+            (n1 @ Num::Float(_), n0) | (n1, n0 @ Num::Float(_)) =>
+                Nums::Floats(n1.to_float(), n0.to_float()),
+            (n1 @ Num::Ratio(_), n0) | (n1, n0 @ Num::Ratio(_)) =>
+                Nums::Ratios(n1.into_ratio(), n0.into_ratio()),
+            (n1, n0) => Nums::Bigs(n1.into_big(), n0.into_big()),
+        }
+    }
+
+    // None if either is nan.
+    fn ordering(&self) -> Option<Ordering> {
+        match self {
+            Nums::Ints(n1, n0) => n1.partial_cmp(n0),
+            Nums::Bigs(b1, b0) => b1.partial_cmp(b0),
+            Nums::Ratios(r1, r0) => r1.partial_cmp(r0),
+            Nums::Floats(x1, x0) => x1.partial_cmp(x0),
+        }
+    }
+}
+
+// The numeric words look at their operands before taking them off the
+// stack, so that if they fail (e.g. on overflow) the stack is unchanged.
+fn peek_num(stack: &Stack, word: &str) -> Result<Num, VmError> {
+    require(stack, word, 1)?;
+    let top = &stack[stack.len() - 1];
+    Num::from_prim(top).ok_or_else(|| type_mismatch(word, "number", top))
+}
+
+fn peek_num_pair(stack: &Stack, word: &str) -> Result<Nums, VmError> {
+    require(stack, word, 2)?;
+    let n = stack.len();
+    match (Num::from_prim(&stack[n - 2]), Num::from_prim(&stack[n - 1])) {
+        (Some(n1), Some(n0)) => Ok(Nums::new(n1, n0)),
+        (Some(_), None) => Err(type_mismatch(word, "number", &stack[n - 1])),
+        (None, _) => Err(type_mismatch(word, "number", &stack[n - 2])),
+    }
+}
+
+// Check that the top `n` values are ints (of either size), for the words
+// that make no sense on floats.
+fn require_ints(stack: &Stack, word: &str, n: usize) -> Result<(), VmError> {
+    require(stack, word, n)?;
+    for prim in &stack[stack.len() - n..] {
+        match prim {
+            Prim::Int(_) | Prim::BigInt(_) => (),
+            bad => return Err(type_mismatch(word, "int", bad)),
+        }
+    }
+    Ok(())
+}
+
+fn pop_num(stack: &mut Stack, word: &str) -> Result<Num, VmError> {
+    let num = peek_num(stack, word)?;
+    stack.pop();
+    Ok(num)
+}
+
+fn pop_num_pair(stack: &mut Stack, word: &str) -> Result<Nums, VmError> {
+    let nums = peek_num_pair(stack, word)?;
+    let n = stack.len();
+    stack.truncate(n - 2);
+    Ok(nums)
+}
+
+// Pop a number as a float, promoting an int.
+fn pop_float(stack: &mut Stack, word: &str) -> Result<f64, VmError> {
+    pop_num(stack, word).map(|num| num.to_float())
+}
+
+fn push_float(vm: &mut Vm, value: f64) -> Result<(), VmError> {
+    vm.stack.push(Prim::Float(value));
+    Ok(())
+}
+
+// Shared by the ordering comparisons, which work on any two numbers.
+fn compare(vm: &mut Vm, word: &str, test: fn(Option<Ordering>) -> bool) -> Result<(), VmError> {
+    let style = vm.flag_style;
+    let nums = pop_num_pair(&mut vm.stack, word)?;
+    vm.stack.push(style.flag(test(nums.ordering())));
+    Ok(())
+}
+
+// Replace the top `n` values with `results`, once a word has succeeded.
+fn replace_top(stack: &mut Stack, n: usize, results: Vec<Prim>) {
+    let len = stack.len();
+    stack.truncate(len - n);
+    stack.extend(results);
+}
+
+// Apply a word to the number on top of the stack. Int results are
+// computed exactly (in i128 or as big ints) and then narrowed according
+// to the vm's overflow policy, which is passed along to `op`.
+fn arith1<F>(vm: &mut Vm, word: &str, op: F) -> Result<(), VmError>
+    where F: FnOnce(Num, OverflowPolicy) -> Result<Prim, VmError>
+{
+    let num = peek_num(&vm.stack, word)?;
+    let result = op(num, vm.overflow_policy)?;
+    replace_top(&mut vm.stack, 1, vec![result]);
+    Ok(())
+}
+
+// Like `arith1`, for a word that takes two numbers.
+fn arith2<F>(vm: &mut Vm, word: &str, op: F) -> Result<(), VmError>
+    where F: FnOnce(Nums, OverflowPolicy) -> Result<Prim, VmError>
+{
+    let nums = peek_num_pair(&vm.stack, word)?;
+    let result = op(nums, vm.overflow_policy)?;
+    replace_top(&mut vm.stack, 2, vec![result]);
+    Ok(())
+}
+
+fn bi_add(vm: &mut Vm) -> Result<(), VmError> {
+    arith2(vm, "+", |nums, policy| match nums {
+        Nums::Ints(n1, n0) => policy.narrow("+", i128::from(n1) + i128::from(n0)),
+        Nums::Bigs(b1, b0) => policy.narrow_big("+", b1 + b0),
+        Nums::Ratios(r1, r0) => ratio_to_prim(policy, "+", r1 + r0),
+        Nums::Floats(x1, x0) => Ok(Prim::Float(x1 + x0)),
+    })
+}
+
+fn bi_mult(vm: &mut Vm) -> Result<(), VmError> {
+    arith2(vm, "*", |nums, policy| match nums {
+        Nums::Ints(n1, n0) => policy.narrow("*", i128::from(n1) * i128::from(n0)),
+        Nums::Bigs(b1, b0) => policy.narrow_big("*", b1 * b0),
+        Nums::Ratios(r1, r0) => ratio_to_prim(policy, "*", r1 * r0),
+        Nums::Floats(x1, x0) => Ok(Prim::Float(x1 * x0)),
+    })
+}
+
+fn bi_sub(vm: &mut Vm) -> Result<(), VmError> {
+    arith2(vm, "-", |nums, policy| match nums {
+        Nums::Ints(n1, n0) => policy.narrow("-", i128::from(n1) - i128::from(n0)),
+        Nums::Bigs(b1, b0) => policy.narrow_big("-", b1 - b0),
+        Nums::Ratios(r1, r0) => ratio_to_prim(policy, "-", r1 - r0),
+        Nums::Floats(x1, x0) => Ok(Prim::Float(x1 - x0)),
+    })
+}
 
-fn bi_add(stack: &mut Stack) {
-    let i0 = stack.pop().expect("Called + on empty stack");
-    let i1 = stack.pop().expect("Called + on stack with one element");
-    let out = match (i0, i1) {
-        (Prim::Int(n0), Prim::Int(n1)) =>
-          Prim::Int(n0 + n1),
-        (bad0, bad1) =>
-          panic!("Called + without int args at top of stack: {0:?}, {1:?}", bad1, bad0),
+
+// Division is floored, as in most modern Forths: the quotient is rounded
+// towards negative infinity, so the remainder has the sign of the divisor
+// (e.g. `-7 2 /mod` gives 1 -4, where symmetric division would give -1 -3;
+// `sm/rem` divides that way). Division by zero is an error. The arithmetic is done in i128 so that
+// `*/` can use the full product as Forth's double-cell intermediate.
+fn floored_div_mod(word: &str, n: i128, d: i128) -> Result<(i128, i128), VmError> {
+    if d == 0 {
+        return Err(VmError::DivisionByZero(word.to_string()));
+    }
+    let (mut q, mut r) = (n / d, n % d);
+    if r != 0 && (r < 0) != (d < 0) {
+        q -= 1;
+        r += d;
+    }
+    Ok((q, r))
+}
+
+fn floored_div_mod_big(word: &str, n: &BigInt, d: &BigInt) -> Result<(BigInt, BigInt), VmError> {
+    if d.is_zero() {
+        return Err(VmError::DivisionByZero(word.to_string()));
+    }
+    Ok(n.div_mod_floor(d))
+}
+
+// Symmetric division: the quotient is rounded towards zero, so the
+// remainder has the sign of the dividend.
+fn symmetric_div_mod(word: &str, n: i128, d: i128) -> Result<(i128, i128), VmError> {
+    if d == 0 {
+        return Err(VmError::DivisionByZero(word.to_string()));
+    }
+    Ok((n / d, n % d))
+}
+
+fn symmetric_div_mod_big(word: &str, n: &BigInt, d: &BigInt) -> Result<(BigInt, BigInt), VmError> {
+    if d.is_zero() {
+        return Err(VmError::DivisionByZero(word.to_string()));
+    }
+    Ok(n.div_rem(d))
+}
+
+// Divide the top two ints, giving the quotient and the remainder. The
+// remainder always fits (it's smaller than the divisor), but the quotient
+// may not, so it's narrowed separately: `mod` doesn't care if it overflows.
+// Only `sm/rem` divides symmetrically.
+fn int_div_mod(vm: &Vm, word: &str, symmetric: bool) -> Result<(Result<Prim, VmError>, Prim), VmError> {
+    require_ints(&vm.stack, word, 2)?;
+    let policy = vm.overflow_policy;
+    match peek_num_pair(&vm.stack, word)? {
+        Nums::Ints(n1, n0) => {
+            let (n1, n0) = (i128::from(n1), i128::from(n0));
+            let (q, r) = if symmetric {
+                symmetric_div_mod(word, n1, n0)?
+            } else {
+                floored_div_mod(word, n1, n0)?
+            };
+            Ok((policy.narrow(word, q), Prim::Int(r as i64)))
+        },
+        Nums::Bigs(b1, b0) => {
+            let (q, r) = if symmetric {
+                symmetric_div_mod_big(word, &b1, &b0)?
+            } else {
+                floored_div_mod_big(word, &b1, &b0)?
+            };
+            Ok((policy.narrow_big(word, q), big_to_prim(r)))
+        },
+        Nums::Ratios(..) | Nums::Floats(..) => unreachable!("Checked for ints above"),
+    }
+}
+
+// Divide exactly, giving a ratio unless the result is a whole number.
+fn exact_div(policy: OverflowPolicy, word: &str, r1: BigRational, r0: BigRational) -> Result<Prim, VmError> {
+    if r0.is_zero() {
+        return Err(VmError::DivisionByZero(word.to_string()));
+    }
+    ratio_to_prim(policy, word, r1 / r0)
+}
+
+// On ints `/` is floored division, unless the vm is set to divide ints
+// exactly. Ratios always divide exactly. As soon as a float is involved
+// it's ordinary float division, where dividing by zero gives inf or nan.
+fn bi_div(vm: &mut Vm) -> Result<(), VmError> {
+    let policy = vm.overflow_policy;
+    let exact = vm.int_division == IntDivision::Exact;
+    let result = match peek_num_pair(&vm.stack, "/")? {
+        Nums::Floats(x1, x0) => Prim::Float(x1 / x0),
+        Nums::Ratios(r1, r0) => exact_div(policy, "/", r1, r0)?,
+        Nums::Ints(n1, n0) if exact => exact_div(policy, "/", ratio(n1), ratio(n0))?,
+        Nums::Bigs(b1, b0) if exact =>
+            exact_div(policy, "/", BigRational::from_integer(b1), BigRational::from_integer(b0))?,
+        _ => int_div_mod(vm, "/", false)?.0?,
     };
-    stack.push(out);
+    replace_top(&mut vm.stack, 2, vec![result]);
+    Ok(())
 }
 
+fn ratio(n: i64) -> BigRational {
+    BigRational::from_integer(BigInt::from(n))
+}
 
-fn bi_mult(stack: &mut Stack) {
-    let i0 = stack.pop().expect("Called + on empty stack");
-    let i1 = stack.pop().expect("Called + on stack with one element");
-    let out = match (i0, i1) {
-        (Prim::Int(n0), Prim::Int(n1)) =>
-          Prim::Int(n0 * n1),
-        (bad0, bad1) =>
-          panic!("Called + without int args at top of stack: {0:?}, {1:?}", bad1, bad0),
+fn bi_mod(vm: &mut Vm) -> Result<(), VmError> {
+    let (_, r) = int_div_mod(vm, "mod", false)?;
+    replace_top(&mut vm.stack, 2, vec![r]);
+    Ok(())
+}
+
+// ( n1 n2 -- rem quot )
+fn bi_div_mod(vm: &mut Vm) -> Result<(), VmError> {
+    let (q, r) = int_div_mod(vm, "/mod", false)?;
+    replace_top(&mut vm.stack, 2, vec![r, q?]);
+    Ok(())
+}
+
+// ( n1 n2 -- rem quot ) Floored division, like `/mod`. Forth's `fm/mod`
+// takes a double-cell dividend; we have no double cells (big ints cover
+// that range), so it takes an ordinary int.
+fn bi_fm_mod(vm: &mut Vm) -> Result<(), VmError> {
+    let (q, r) = int_div_mod(vm, "fm/mod", false)?;
+    replace_top(&mut vm.stack, 2, vec![r, q?]);
+    Ok(())
+}
+
+// ( n1 n2 -- rem quot ) Symmetric division; otherwise like `fm/mod`.
+fn bi_sm_rem(vm: &mut Vm) -> Result<(), VmError> {
+    let (q, r) = int_div_mod(vm, "sm/rem", true)?;
+    replace_top(&mut vm.stack, 2, vec![r, q?]);
+    Ok(())
+}
+
+// ( n1 n2 n3 -- n1*n2/n3 ), without overflow in the intermediate product.
+// Like `/`, the division is exact if the vm is set to divide ints exactly.
+fn bi_mult_div(vm: &mut Vm) -> Result<(), VmError> {
+    require_ints(&vm.stack, "*/", 3)?;
+    let policy = vm.overflow_policy;
+    let exact = vm.int_division == IntDivision::Exact;
+    let n = vm.stack.len();
+    let num = |prim: &Prim| Num::from_prim(prim).expect("Checked for ints above");
+    let q = match (&vm.stack[n - 3], &vm.stack[n - 2], &vm.stack[n - 1]) {
+        (p2, p1, p0) if exact => {
+            let product = num(p2).into_ratio() * num(p1).into_ratio();
+            exact_div(policy, "*/", product, num(p0).into_ratio())?
+        },
+        (Prim::Int(n2), Prim::Int(n1), Prim::Int(n0)) => {
+            let (q, _) = floored_div_mod("*/", i128::from(*n2) * i128::from(*n1), i128::from(*n0))?;
+            policy.narrow("*/", q)?
+        },
+        (p2, p1, p0) => {
+            let product = num(p2).into_big() * num(p1).into_big();
+            let (q, _) = floored_div_mod_big("*/", &product, &num(p0).into_big())?;
+            policy.narrow_big("*/", q)?
+        },
+    };
+    replace_top(&mut vm.stack, 3, vec![q]);
+    Ok(())
+}
+
+// ( n1 n2 -- n1/n2 ), dividing exactly whatever the vm's division mode.
+fn bi_ratio(vm: &mut Vm) -> Result<(), VmError> {
+    require(&vm.stack, "ratio", 2)?;
+    for prim in &vm.stack[vm.stack.len() - 2..] {
+        if let Prim::Float(_) = prim {
+            return Err(type_mismatch("ratio", "int or ratio", prim));
+        }
+    }
+    let policy = vm.overflow_policy;
+    let result = match peek_num_pair(&vm.stack, "ratio")? {
+        Nums::Ints(n1, n0) => exact_div(policy, "ratio", ratio(n1), ratio(n0))?,
+        Nums::Bigs(b1, b0) =>
+            exact_div(policy, "ratio", BigRational::from_integer(b1), BigRational::from_integer(b0))?,
+        Nums::Ratios(r1, r0) => exact_div(policy, "ratio", r1, r0)?,
+        Nums::Floats(..) => unreachable!("Checked for floats above"),
     };
-    stack.push(out);
-}
\ No newline at end of file
+    replace_top(&mut vm.stack, 2, vec![result]);
+    Ok(())
+}
+
+// The numerator and denominator of a ratio, in lowest terms. An int is
+// its own numerator, with a denominator of 1.
+fn bi_numerator(vm: &mut Vm) -> Result<(), VmError> {
+    ratio_part(vm, "numerator", |r| r.numer().clone())
+}
+
+fn bi_denominator(vm: &mut Vm) -> Result<(), VmError> {
+    ratio_part(vm, "denominator", |r| r.denom().clone())
+}
+
+fn ratio_part(vm: &mut Vm, word: &str, part: fn(&BigRational) -> BigInt) -> Result<(), VmError> {
+    arith1(vm, word, |num, _| match num {
+        Num::Float(x) => Err(type_mismatch(word, "int or ratio", &Prim::Float(x))),
+        exact => Ok(big_to_prim(part(&exact.into_ratio()))),
+    })
+}
+
+// Convert any number to an int, truncating towards zero. Results too big
+// for an int are handled by the overflow policy, but inf and nan are
+// always an error.
+fn bi_to_int(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, ">int", |num, policy| match num {
+        Num::Int(n) => Ok(Prim::Int(n)),
+        Num::Big(b) => policy.narrow_big(">int", b),
+        Num::Ratio(r) => policy.narrow_big(">int", r.trunc().to_integer()),
+        Num::Float(x) => match BigInt::from_f64(x.trunc()) {
+            Some(b) => policy.narrow_big(">int", b),
+            None => Err(type_mismatch(">int", "finite number", &Prim::Float(x))),
+        },
+    })
+}
+
+fn bi_to_float(vm: &mut Vm) -> Result<(), VmError> {
+    let x = pop_float(&mut vm.stack, ">float")?;
+    push_float(vm, x)
+}
+
+fn bi_negate(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, "negate", |num, policy| match num {
+        Num::Int(n) => policy.narrow("negate", -i128::from(n)),
+        Num::Big(b) => policy.narrow_big("negate", -b),
+        Num::Ratio(r) => Ok(Prim::Ratio(-r)),
+        Num::Float(x) => Ok(Prim::Float(-x)),
+    })
+}
+
+fn bi_abs(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, "abs", |num, policy| match num {
+        Num::Int(n) => policy.narrow("abs", i128::from(n).abs()),
+        Num::Big(b) => policy.narrow_big("abs", b.abs()),
+        Num::Ratio(r) => Ok(Prim::Ratio(r.abs())),
+        Num::Float(x) => Ok(Prim::Float(x.abs())),
+    })
+}
+
+fn bi_min(vm: &mut Vm) -> Result<(), VmError> {
+    arith2(vm, "min", |nums, _| Ok(match nums {
+        Nums::Ints(n1, n0) => Prim::Int(n1.min(n0)),
+        Nums::Bigs(b1, b0) => big_to_prim(b1.min(b0)),
+        Nums::Ratios(r1, r0) => ratio_to_int_or_ratio(r1.min(r0)),
+        Nums::Floats(x1, x0) => Prim::Float(x1.min(x0)),
+    }))
+}
+
+fn bi_max(vm: &mut Vm) -> Result<(), VmError> {
+    arith2(vm, "max", |nums, _| Ok(match nums {
+        Nums::Ints(n1, n0) => Prim::Int(n1.max(n0)),
+        Nums::Bigs(b1, b0) => big_to_prim(b1.max(b0)),
+        Nums::Ratios(r1, r0) => ratio_to_int_or_ratio(r1.max(r0)),
+        Nums::Floats(x1, x0) => Prim::Float(x1.max(x0)),
+    }))
+}
+
+// A big int that may have come from promoting an i64, as a Prim.
+fn big_to_prim(value: BigInt) -> Prim {
+    match value.to_i64() {
+        Some(n) => Prim::Int(n),
+        None => Prim::BigInt(value),
+    }
+}
+
+// A ratio that may have come from promoting an int, as a Prim.
+fn ratio_to_int_or_ratio(value: BigRational) -> Prim {
+    if value.is_integer() {
+        big_to_prim(value.to_integer())
+    } else {
+        Prim::Ratio(value)
+    }
+}
+
+// The result of exact arithmetic. Ratios are only kept for fractions:
+// a whole number becomes an int, subject to the overflow policy.
+fn ratio_to_prim(policy: OverflowPolicy, word: &str, value: BigRational) -> Result<Prim, VmError> {
+    if value.is_integer() {
+        policy.narrow_big(word, value.to_integer())
+    } else {
+        Ok(Prim::Ratio(value))
+    }
+}
+
+// `1+`, `1-` and `2*` are shorthands, so they work on any number.
+fn bi_one_plus(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, "1+", |num, policy| match num {
+        Num::Int(n) => policy.narrow("1+", i128::from(n) + 1),
+        Num::Big(b) => policy.narrow_big("1+", b + 1),
+        Num::Ratio(r) => ratio_to_prim(policy, "1+", r + BigInt::from(1)),
+        Num::Float(x) => Ok(Prim::Float(x + 1.0)),
+    })
+}
+
+fn bi_one_minus(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, "1-", |num, policy| match num {
+        Num::Int(n) => policy.narrow("1-", i128::from(n) - 1),
+        Num::Big(b) => policy.narrow_big("1-", b - 1),
+        Num::Ratio(r) => ratio_to_prim(policy, "1-", r - BigInt::from(1)),
+        Num::Float(x) => Ok(Prim::Float(x - 1.0)),
+    })
+}
+
+fn bi_two_mult(vm: &mut Vm) -> Result<(), VmError> {
+    arith1(vm, "2*", |num, policy| match num {
+        Num::Int(n) => policy.narrow("2*", i128::from(n) * 2),
+        Num::Big(b) => policy.narrow_big("2*", b * 2),
+        Num::Ratio(r) => ratio_to_prim(policy, "2*", r * BigInt::from(2)),
+        Num::Float(x) => Ok(Prim::Float(x * 2.0)),
+    })
+}
+
+// Like Forth, `2/` is an arithmetic shift, so it rounds towards negative
+// infinity (consistent with `/`). It can't overflow.
+fn bi_two_div(vm: &mut Vm) -> Result<(), VmError> {
+    require_ints(&vm.stack, "2/", 1)?;
+    arith1(vm, "2/", |num, _| match num {
+        Num::Int(n) => Ok(Prim::Int(n >> 1)),
+        Num::Big(b) => Ok(big_to_prim(b.div_floor(&BigInt::from(2)))),
+        Num::Ratio(_) | Num::Float(_) => unreachable!("Checked for ints above"),
+    })
+}
+
+
+// The float words take any number, promoting an int to a float. Like
+// the float operations themselves they never fail on a bad value: e.g.
+// the square root of a negative number is nan.
+fn float_fn(vm: &mut Vm, word: &str, f: fn(f64) -> f64) -> Result<(), VmError> {
+    let x = pop_float(&mut vm.stack, word)?;
+    push_float(vm, f(x))
+}
+
+fn bi_sqrt(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "sqrt", f64::sqrt)
+}
+
+fn bi_sin(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "sin", f64::sin)
+}
+
+fn bi_cos(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "cos", f64::cos)
+}
+
+fn bi_exp(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "exp", f64::exp)
+}
+
+fn bi_ln(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "ln", f64::ln)
+}
+
+fn bi_floor(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "floor", f64::floor)
+}
+
+// Halfway cases round away from zero.
+fn bi_round(vm: &mut Vm) -> Result<(), VmError> {
+    float_fn(vm, "round", f64::round)
+}
+
+// Print the top of the stack as a float, and drop it.
+fn bi_print_float(vm: &mut Vm) -> Result<(), VmError> {
+    let x = pop_float(&mut vm.stack, "f.")?;
+    vm.write_output("f.", &format!(" {:?}", x))
+}
+
+fn bi_int_to_float(vm: &mut Vm) -> Result<(), VmError> {
+    let x = pop_float(&mut vm.stack, "s>f")?;
+    push_float(vm, x)
+}
+
+// Truncate a float towards zero. Results too big for an int are handled
+// by the overflow policy, but inf and nan are always an error.
+fn bi_float_to_int(vm: &mut Vm) -> Result<(), VmError> {
+    let policy = vm.overflow_policy;
+    let stack = &mut vm.stack;
+    require(stack, "f>s", 1)?;
+    let value = match &stack[stack.len() - 1] {
+        Prim::Float(x) if x.is_finite() =>
+            BigInt::from_f64(x.trunc()).expect("Finite floats convert to big ints"),
+        bad => return Err(type_mismatch("f>s", "finite float", bad)),
+    };
+    let n = policy.narrow_big("f>s", value)?;
+    replace_top(stack, 1, vec![n]);
+    Ok(())
+}
+
+
+// Numbers are compared by value, so that `1 1.0 =` is true; anything else
+// has to be the same kind of value to be equal.
+fn prims_equal(p1: &Prim, p0: &Prim) -> bool {
+    if let (Prim::Quot(_), Prim::Quot(_)) = (p1, p0) {
+        // Quotations are equal if they hold the same code, wherever it
+        // was written.
+        return p1.clone().without_locs() == p0.clone().without_locs();
+    }
+    match (Num::from_prim(p1), Num::from_prim(p0)) {
+        (Some(n1), Some(n0)) => Nums::new(n1, n0).ordering() == Some(Ordering::Equal),
+        _ => p1 == p0,
+    }
+}
+
+fn bi_eq(vm: &mut Vm) -> Result<(), VmError> {
+    let style = vm.flag_style;
+    let stack = &mut vm.stack;
+    require(stack, "=", 2)?;
+    let i0 = stack.pop().expect("Checked stack depth above");
+    let i1 = stack.pop().expect("Checked stack depth above");
+    stack.push(style.flag(prims_equal(&i1, &i0)));
+    Ok(())
+}
+
+fn bi_ne(vm: &mut Vm) -> Result<(), VmError> {
+    let style = vm.flag_style;
+    let stack = &mut vm.stack;
+    require(stack, "<>", 2)?;
+    let i0 = stack.pop().expect("Checked stack depth above");
+    let i1 = stack.pop().expect("Checked stack depth above");
+    stack.push(style.flag(!prims_equal(&i1, &i0)));
+    Ok(())
+}
+
+fn bi_lt(vm: &mut Vm) -> Result<(), VmError> {
+    compare(vm, "<", |ordering| ordering == Some(Ordering::Less))
+}
+
+fn bi_gt(vm: &mut Vm) -> Result<(), VmError> {
+    compare(vm, ">", |ordering| ordering == Some(Ordering::Greater))
+}
+
+fn bi_le(vm: &mut Vm) -> Result<(), VmError> {
+    compare(vm, "<=", |ordering| matches!(ordering, Some(Ordering::Less) | Some(Ordering::Equal)))
+}
+
+fn bi_ge(vm: &mut Vm) -> Result<(), VmError> {
+    compare(vm, ">=", |ordering| matches!(ordering, Some(Ordering::Greater) | Some(Ordering::Equal)))
+}
+
+// ( x -- flag ), true if x is zero. On a bool this is logical negation.
+fn bi_zero_eq(vm: &mut Vm) -> Result<(), VmError> {
+    let style = vm.flag_style;
+    let stack = &mut vm.stack;
+    require(stack, "0=", 1)?;
+    let is_zero = match &stack[stack.len() - 1] {
+        Prim::Bool(b) => {
+            let b = *b;
+            stack.pop();
+            !b
+        },
+        _ => match pop_num(stack, "0=")? {
+            Num::Int(n) => n == 0,
+            // Big ints are only used for values that don't fit in an i64,
+            // and ratios are never whole.
+            Num::Big(_) | Num::Ratio(_) => false,
+            Num::Float(x) => x == 0.0,
+        },
+    };
+    stack.push(style.flag(is_zero));
+    Ok(())
+}
+
+fn bi_zero_lt(vm: &mut Vm) -> Result<(), VmError> {
+    let style = vm.flag_style;
+    let stack = &mut vm.stack;
+    let negative = match pop_num(stack, "0<")? {
+        Num::Int(n) => n < 0,
+        Num::Big(b) => b.is_negative(),
+        Num::Ratio(r) => r.is_negative(),
+        Num::Float(x) => x < 0.0,
+    };
+    stack.push(style.flag(negative));
+    Ok(())
+}
+
+// Shared by `and`, `or` and `xor`: on two bools they're the logical
+// operations, and on two ints they're bitwise (which, for Forth-style
+// -1/0 flags, amounts to the same thing).
+fn logic_op(
+    vm: &mut Vm,
+    word: &str,
+    bool_op: fn(bool, bool) -> bool,
+    int_op: fn(i64, i64) -> i64,
+) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, word, 2)?;
+    let n = stack.len();
+    let result = match (&stack[n - 2], &stack[n - 1]) {
+        (Prim::Bool(b1), Prim::Bool(b0)) => Prim::Bool(bool_op(*b1, *b0)),
+        (Prim::Int(n1), Prim::Int(n0)) => Prim::Int(int_op(*n1, *n0)),
+        (Prim::Bool(_), bad) => return Err(type_mismatch(word, "bool", bad)),
+        (Prim::Int(_), bad) => return Err(type_mismatch(word, "int", bad)),
+        (bad, _) => return Err(type_mismatch(word, "int or bool", bad)),
+    };
+    stack.truncate(n - 2);
+    stack.push(result);
+    Ok(())
+}
+
+fn bi_and(vm: &mut Vm) -> Result<(), VmError> {
+    logic_op(vm, "and", |b1, b0| b1 && b0, |n1, n0| n1 & n0)
+}
+
+fn bi_or(vm: &mut Vm) -> Result<(), VmError> {
+    logic_op(vm, "or", |b1, b0| b1 || b0, |n1, n0| n1 | n0)
+}
+
+fn bi_xor(vm: &mut Vm) -> Result<(), VmError> {
+    logic_op(vm, "xor", |b1, b0| b1 != b0, |n1, n0| n1 ^ n0)
+}
+
+// Logical not on a bool, and bitwise not on an int.
+fn bi_invert(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    require(stack, "invert", 1)?;
+    let inverted = match &stack[stack.len() - 1] {
+        Prim::Int(n) => Prim::Int(!*n),
+        Prim::Bool(b) => Prim::Bool(!*b),
+        bad => return Err(type_mismatch("invert", "int or bool", bad)),
+    };
+    stack.pop();
+    stack.push(inverted);
+    Ok(())
+}
+
+// The shifts treat an int as 64 bits, so they never overflow: bits shifted
+// out are lost. Shifting by 64 or more shifts everything out, leaving 0
+// (or, for `arshift` of a negative number, -1). A negative shift amount
+// is an error.
+fn pop_shift(stack: &mut Stack, word: &str) -> Result<(i64, u32), VmError> {
+    require(stack, word, 2)?;
+    if let Prim::Int(k) = &stack[stack.len() - 1] {
+        if *k < 0 {
+            return Err(type_mismatch(word, "non-negative int", &Prim::Int(*k)));
+        }
+    }
+    let (n, k) = pop_int_pair(stack, word)?;
+    Ok((n, k.min(64) as u32))
+}
+
+fn bi_lshift(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let (n, k) = pop_shift(stack, "lshift")?;
+    stack.push(Prim::Int(n.checked_shl(k).unwrap_or(0)));
+    Ok(())
+}
+
+// A logical shift: zeros come in at the top.
+fn bi_rshift(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let (n, k) = pop_shift(stack, "rshift")?;
+    stack.push(Prim::Int((n as u64).checked_shr(k).unwrap_or(0) as i64));
+    Ok(())
+}
+
+// An arithmetic shift: copies of the sign bit come in at the top.
+fn bi_arshift(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let (n, k) = pop_shift(stack, "arshift")?;
+    stack.push(Prim::Int(n >> k.min(63)));
+    Ok(())
+}
+
+// The number of set bits, counting a negative int in two's complement.
+fn bi_popcount(vm: &mut Vm) -> Result<(), VmError> {
+    let stack = &mut vm.stack;
+    let n = pop_int(stack, "popcount")?;
+    stack.push(Prim::Int(i64::from(n.count_ones())));
+    Ok(())
+}
+
+fn bi_true(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.flag_style.flag(true);
+    vm.stack.push(value);
+    Ok(())
+}
+
+fn bi_false(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.flag_style.flag(false);
+    vm.stack.push(value);
+    Ok(())
+}
+
+
+// The index of the `depth`th innermost loop (0 is the innermost).
+fn loop_index(vm: &mut Vm, word: &str, depth: usize) -> Result<(), VmError> {
+    let n = vm.loops.len();
+    if n <= depth {
+        return Err(VmError::NotInLoop(word.to_string()));
+    }
+    let index = vm.loops[n - 1 - depth].index;
+    vm.stack.push(Prim::Int(index));
+    Ok(())
+}
+
+fn bi_i(vm: &mut Vm) -> Result<(), VmError> {
+    loop_index(vm, "i", 0)
+}
+
+fn bi_j(vm: &mut Vm) -> Result<(), VmError> {
+    loop_index(vm, "j", 1)
+}
+
+// Exit the innermost `do` loop right away, by dropping its frame and
+// skipping the rest of the program up to the end of its body. Words that
+// we leave from the middle of (e.g. a word called in the loop body that
+// calls `leave`) keep their end markers, so their return stack is still
+// checked once the loop is gone.
+fn bi_leave(vm: &mut Vm) -> Result<(), VmError> {
+    if vm.loops.pop().is_none() {
+        return Err(VmError::NotInLoop("leave".to_string()));
+    }
+    let mut word_ends = Vec::new();
+    while let Some(instr) = vm.prog.pop() {
+        match instr {
+            Instr::LoopEnd { .. } => break,
+            Instr::WordEnd { .. } => word_ends.push(instr),
+            _ => (),
+        }
+    }
+    vm.prog.extend(word_ends.into_iter().rev());
+    Ok(())
+}
+
+
+// Move the top `n` values from one stack to the other, keeping their order.
+fn move_values(from: &mut Stack, to: &mut Stack, n: usize) {
+    let moved = from.split_off(from.len() - n);
+    to.extend(moved);
+}
+
+fn bi_to_r(vm: &mut Vm) -> Result<(), VmError> {
+    require(&vm.stack, ">r", 1)?;
+    move_values(&mut vm.stack, &mut vm.rstack, 1);
+    Ok(())
+}
+
+fn bi_r_from(vm: &mut Vm) -> Result<(), VmError> {
+    require_r(&vm.rstack, "r>", 1)?;
+    move_values(&mut vm.rstack, &mut vm.stack, 1);
+    Ok(())
+}
+
+fn bi_r_fetch(vm: &mut Vm) -> Result<(), VmError> {
+    require_r(&vm.rstack, "r@", 1)?;
+    let top = vm.rstack[vm.rstack.len() - 1].clone();
+    vm.stack.push(top);
+    Ok(())
+}
+
+fn bi_two_to_r(vm: &mut Vm) -> Result<(), VmError> {
+    require(&vm.stack, "2>r", 2)?;
+    move_values(&mut vm.stack, &mut vm.rstack, 2);
+    Ok(())
+}
+
+fn bi_two_r_from(vm: &mut Vm) -> Result<(), VmError> {
+    require_r(&vm.rstack, "2r>", 2)?;
+    move_values(&mut vm.rstack, &mut vm.stack, 2);
+    Ok(())
+}
+
+fn bi_rdrop(vm: &mut Vm) -> Result<(), VmError> {
+    require_r(&vm.rstack, "rdrop", 1)?;
+    vm.rstack.pop();
+    Ok(())
+}
@@ -0,0 +1,441 @@
+// Words that work with quotations, `[ ... ]`. Calling a quotation works
+// just like calling a word: its atoms go on the program stack. Words that
+// need to do something after a quotation has run (e.g. `times` going round
+// again) put an `Instr::Resume` under it, so nothing here ever runs the vm
+// recursively.
+//
+// A quotation can also be used as a sequence, e.g. by `each` and `map`.
+// Its elements are the values of its literals; any other atom, such as a
+// word, is an element in the form of a one-atom quotation.
+
+use std::rc::Rc;
+
+use either::Left;
+
+use super::builtin_words::{pop_flag, require, type_mismatch};
+use super::{expand_atoms, Instr};
+use super::{Atom, AtomKind, Builtin, Loc, Prim, Stack, Vm, VmError, WordExec, WordMap};
+
+// Like Factor's words, these belong to a vocabulary, and each can be called
+// by its qualified name, e.g. `combinators:map`. All but `if`, `while` and
+// `loop` can be called by their plain names too; those three names belong
+// to Forth's control structures, which the parser handles.
+const VOCABULARY: &str = "combinators";
+const QUALIFIED_ONLY: &[&str] = &["if", "while", "loop"];
+
+pub fn add_words(word_map: &mut WordMap) {
+    let words: &[(&str, Builtin)] = &[
+        ("call", bi_call),
+        ("dip", bi_dip),
+        ("keep", bi_keep),
+        ("bi", bi_bi),
+        ("tri", bi_tri),
+        ("cleave", bi_cleave),
+        ("spread", bi_spread),
+        ("compose", bi_compose),
+        ("curry", bi_curry),
+        ("if", bi_if),
+        ("when", bi_when),
+        ("unless", bi_unless),
+        ("times", bi_times),
+        ("while", bi_while),
+        ("loop", bi_loop),
+        ("each", bi_each),
+        ("map", bi_map),
+        ("filter", bi_filter),
+        ("reduce", bi_reduce),
+    ];
+    for (name, builtin) in words {
+        let exec: WordExec = Rc::new(*builtin);
+        word_map.insert(format!("{}:{}", VOCABULARY, name), Left(exec.clone()));
+        if !QUALIFIED_ONLY.contains(name) {
+            word_map.insert(name.to_string(), Left(exec));
+        }
+    }
+}
+
+// Check that the top `n` values are quotations, and that there are
+// `under` more values beneath them for the quotations to work on.
+pub fn require_quots(stack: &Stack, word: &str, n: usize, under: usize) -> Result<(), VmError> {
+    require(stack, word, n + under)?;
+    for prim in &stack[stack.len() - n..] {
+        if let Prim::Quot(_) = prim {
+            continue;
+        }
+        return Err(type_mismatch(word, "quotation", prim));
+    }
+    Ok(())
+}
+
+// Pop a quotation that `require_quots` has already checked for.
+pub fn pop_quot(stack: &mut Stack) -> Vec<Atom> {
+    match stack.pop() {
+        Some(Prim::Quot(body)) => body,
+        _ => unreachable!("Checked for a quotation above"),
+    }
+}
+
+// Put a value on the program stack, so that it's pushed onto the data
+// stack when the vm gets to it, e.g. once a quotation has run.
+fn push_lit(vm: &mut Vm, prim: Prim) {
+    vm.prog.push(Instr::Push(prim));
+}
+
+// The elements of a quotation used as a sequence.
+fn elements(body: Vec<Atom>) -> Vec<Prim> {
+    body.into_iter()
+        .map(|atom| match atom.kind {
+            AtomKind::Lit(prim) => prim,
+            _ => Prim::Quot(vec![atom]),
+        })
+        .collect()
+}
+
+// Check a quotation of quotations, e.g. for `cleave`, returning their bodies.
+fn quot_elements(word: &str, body: Vec<Atom>) -> Result<Vec<Vec<Atom>>, VmError> {
+    elements(body).into_iter()
+        .map(|prim| match prim {
+            Prim::Quot(body) => Ok(body),
+            bad => Err(type_mismatch(word, "quotation", &bad)),
+        })
+        .collect()
+}
+
+// ( quot -- ) Like a word, the quotation's body goes on the program stack.
+fn bi_call(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "call", 1, 0)?;
+    let body = pop_quot(&mut vm.stack);
+    expand_atoms(&mut vm.prog, &body);
+    Ok(())
+}
+
+// ( x quot -- x ) Call the quotation with x out of the way.
+fn bi_dip(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "dip", 1, 1)?;
+    let body = pop_quot(&mut vm.stack);
+    let x = vm.stack.pop().expect("Checked stack depth above");
+    push_lit(vm, x);
+    expand_atoms(&mut vm.prog, &body);
+    Ok(())
+}
+
+// ( x quot -- x ) Call the quotation on x, then push x again.
+fn bi_keep(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "keep", 1, 1)?;
+    let body = pop_quot(&mut vm.stack);
+    let x = vm.stack[vm.stack.len() - 1].clone();
+    push_lit(vm, x);
+    expand_atoms(&mut vm.prog, &body);
+    Ok(())
+}
+
+// ( x p q -- ) Call each quotation on its own copy of x.
+fn bi_bi(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "bi", 2, 1)?;
+    let q = pop_quot(&mut vm.stack);
+    let p = pop_quot(&mut vm.stack);
+    apply_each(vm, vec![p, q]);
+    Ok(())
+}
+
+// ( x p q r -- )
+fn bi_tri(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "tri", 3, 1)?;
+    let r = pop_quot(&mut vm.stack);
+    let q = pop_quot(&mut vm.stack);
+    let p = pop_quot(&mut vm.stack);
+    apply_each(vm, vec![p, q, r]);
+    Ok(())
+}
+
+// ( x [ p q ... ] -- ) Like `bi` and `tri`, for any number of quotations.
+fn bi_cleave(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "cleave", 1, 1)?;
+    let bodies = quot_elements("cleave", peek_quot(&vm.stack))?;
+    vm.stack.pop();
+    apply_each(vm, bodies);
+    Ok(())
+}
+
+// Call each of `bodies` on a copy of the value on top of the stack.
+fn apply_each(vm: &mut Vm, bodies: Vec<Vec<Atom>>) {
+    let x = vm.stack[vm.stack.len() - 1].clone();
+    // The first quotation gets the x that's already on the stack; each of
+    // the others pushes its own copy first.
+    for (i, body) in bodies.iter().enumerate().rev() {
+        expand_atoms(&mut vm.prog, body);
+        if i > 0 {
+            push_lit(vm, x.clone());
+        }
+    }
+}
+
+// ( x1 ... xn [ q1 ... qn ] -- ) Call each quotation on its own value.
+fn bi_spread(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "spread", 1, 0)?;
+    let bodies = quot_elements("spread", peek_quot(&vm.stack))?;
+    require(&vm.stack, "spread", bodies.len() + 1)?;
+    vm.stack.pop();
+    let values = vm.stack.split_off(vm.stack.len() - bodies.len());
+    for (x, body) in values.into_iter().zip(bodies).rev() {
+        expand_atoms(&mut vm.prog, &body);
+        push_lit(vm, x);
+    }
+    Ok(())
+}
+
+// A copy of the quotation on top of the stack, for words that need to
+// check its contents before they take anything off the stack.
+fn peek_quot(stack: &Stack) -> Vec<Atom> {
+    match &stack[stack.len() - 1] {
+        Prim::Quot(body) => body.clone(),
+        _ => unreachable!("Checked for a quotation above"),
+    }
+}
+
+// ( p q -- pq ) A quotation that calls p and then q.
+fn bi_compose(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "compose", 2, 0)?;
+    let second = pop_quot(&mut vm.stack);
+    let mut first = pop_quot(&mut vm.stack);
+    first.extend(second);
+    vm.stack.push(Prim::Quot(first));
+    Ok(())
+}
+
+// ( x quot -- quot' ) A quotation that pushes x and then calls quot.
+fn bi_curry(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "curry", 1, 1)?;
+    let body = pop_quot(&mut vm.stack);
+    let x = vm.stack.pop().expect("Checked stack depth above");
+    let mut curried = vec![Atom::lit(x, Loc::default())];
+    curried.extend(body);
+    vm.stack.push(Prim::Quot(curried));
+    Ok(())
+}
+
+// Pop `n` quotations and the flag beneath them. If the flag is bad, the
+// stack is left as it was.
+fn pop_quots_and_flag(vm: &mut Vm, word: &str, n: usize) -> Result<(bool, Vec<Vec<Atom>>), VmError> {
+    require_quots(&vm.stack, word, n, 1)?;
+    let quots = vm.stack.split_off(vm.stack.len() - n);
+    match pop_flag(&mut vm.stack, word) {
+        Ok(flag) => {
+            let bodies = quots.into_iter()
+                .map(|prim| match prim {
+                    Prim::Quot(body) => body,
+                    _ => unreachable!("Checked for quotations above"),
+                })
+                .collect();
+            Ok((flag, bodies))
+        },
+        Err(err) => {
+            vm.stack.extend(quots);
+            Err(err)
+        },
+    }
+}
+
+// ( flag [ then ] [ else ] -- )
+fn bi_if(vm: &mut Vm) -> Result<(), VmError> {
+    let (flag, mut bodies) = pop_quots_and_flag(vm, "combinators:if", 2)?;
+    let else_body = bodies.pop().expect("Popped two quotations");
+    let then_body = bodies.pop().expect("Popped two quotations");
+    expand_atoms(&mut vm.prog, if flag { &then_body } else { &else_body });
+    Ok(())
+}
+
+// ( flag [ then ] -- )
+fn bi_when(vm: &mut Vm) -> Result<(), VmError> {
+    let (flag, bodies) = pop_quots_and_flag(vm, "when", 1)?;
+    if flag {
+        expand_atoms(&mut vm.prog, &bodies[0]);
+    }
+    Ok(())
+}
+
+// ( flag [ else ] -- )
+fn bi_unless(vm: &mut Vm) -> Result<(), VmError> {
+    let (flag, bodies) = pop_quots_and_flag(vm, "unless", 1)?;
+    if !flag {
+        expand_atoms(&mut vm.prog, &bodies[0]);
+    }
+    Ok(())
+}
+
+// Schedule `resume` to run after `body`, with `state` pushed back onto
+// the data stack for it. This is how the looping words go round again.
+fn call_then_resume(vm: &mut Vm, body: &[Atom], state: Vec<Prim>, resume: Builtin) {
+    vm.prog.push(Instr::Resume(resume));
+    for prim in state.into_iter().rev() {
+        push_lit(vm, prim);
+    }
+    expand_atoms(&mut vm.prog, body);
+}
+
+// ( n [ body ] -- ) Call the body n times.
+fn bi_times(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "times", 1, 1)?;
+    let n = match &vm.stack[vm.stack.len() - 2] {
+        Prim::Int(n) if *n >= 0 => *n,
+        bad => return Err(type_mismatch("times", "non-negative int", bad)),
+    };
+    let body = pop_quot(&mut vm.stack);
+    vm.stack.pop();
+    if n > 0 {
+        let state = vec![Prim::Int(n - 1), Prim::Quot(body.clone())];
+        call_then_resume(vm, &body, state, bi_times);
+    }
+    Ok(())
+}
+
+// ( [ cond ] [ body ] -- ) Call the body for as long as the condition
+// leaves a true flag.
+fn bi_while(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "combinators:while", 2, 0)?;
+    let body = pop_quot(&mut vm.stack);
+    let cond = pop_quot(&mut vm.stack);
+    let state = vec![Prim::Quot(cond.clone()), Prim::Quot(body)];
+    call_then_resume(vm, &cond, state, while_test);
+    Ok(())
+}
+
+// ( flag [ cond ] [ body ] -- ) After `combinators:while` has called the condition.
+fn while_test(vm: &mut Vm) -> Result<(), VmError> {
+    let (flag, mut bodies) = pop_quots_and_flag(vm, "combinators:while", 2)?;
+    if flag {
+        let body = bodies.pop().expect("Popped two quotations");
+        let cond = bodies.pop().expect("Popped two quotations");
+        let state = vec![Prim::Quot(cond.clone()), Prim::Quot(body.clone())];
+        call_then_resume(vm, &cond, state, while_test);
+        expand_atoms(&mut vm.prog, &body);
+    }
+    Ok(())
+}
+
+// ( [ body ] -- ) Call the body until it leaves a false flag.
+fn bi_loop(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "combinators:loop", 1, 0)?;
+    let body = pop_quot(&mut vm.stack);
+    let state = vec![Prim::Quot(body.clone())];
+    call_then_resume(vm, &body, state, loop_test);
+    Ok(())
+}
+
+// ( flag [ body ] -- ) After `combinators:loop` has called the body.
+fn loop_test(vm: &mut Vm) -> Result<(), VmError> {
+    let (flag, mut bodies) = pop_quots_and_flag(vm, "combinators:loop", 1)?;
+    if flag {
+        let body = bodies.pop().expect("Popped a quotation");
+        let state = vec![Prim::Quot(body.clone())];
+        call_then_resume(vm, &body, state, loop_test);
+    }
+    Ok(())
+}
+
+// Pop the quotation on top of the stack and the sequence `under` values
+// below it, for the words that apply a quotation to each element.
+fn pop_seq_and_body(vm: &mut Vm, word: &str, under: usize) -> Result<(Vec<Prim>, Vec<Atom>), VmError> {
+    require_quots(&vm.stack, word, 1, 1 + under)?;
+    let seq_index = vm.stack.len() - 2 - under;
+    match &vm.stack[seq_index] {
+        Prim::Quot(_) => (),
+        bad => return Err(type_mismatch(word, "quotation", bad)),
+    }
+    let body = pop_quot(&mut vm.stack);
+    let seq = match vm.stack.remove(seq_index) {
+        Prim::Quot(seq) => seq,
+        _ => unreachable!("Checked for a quotation above"),
+    };
+    Ok((elements(seq), body))
+}
+
+// Schedule `element body` for each element in turn.
+fn apply_to_elements(vm: &mut Vm, seq: Vec<Prim>, body: &[Atom]) {
+    for element in seq.into_iter().rev() {
+        expand_atoms(&mut vm.prog, body);
+        push_lit(vm, element);
+    }
+}
+
+// ( [ x1 ... xn ] [ body ] -- ) Call the body on each element.
+fn bi_each(vm: &mut Vm) -> Result<(), VmError> {
+    let (seq, body) = pop_seq_and_body(vm, "each", 0)?;
+    apply_to_elements(vm, seq, &body);
+    Ok(())
+}
+
+// ( [ x1 ... xn ] init [ body ] -- result ) Call the body on the running
+// result and each element, e.g. `[ 1 2 3 ] 0 [ + ] reduce`.
+fn bi_reduce(vm: &mut Vm) -> Result<(), VmError> {
+    let (seq, body) = pop_seq_and_body(vm, "reduce", 1)?;
+    apply_to_elements(vm, seq, &body);
+    Ok(())
+}
+
+// ( [ x1 ... xn ] [ body ] -- [ y1 ... yn ] ) Call the body on each
+// element, gathering whatever it leaves into a new quotation.
+fn bi_map(vm: &mut Vm) -> Result<(), VmError> {
+    let (seq, body) = pop_seq_and_body(vm, "map", 0)?;
+    let depth = vm.stack.len();
+    call_then_resume(vm, &[], vec![Prim::Int(depth as i64)], gather_map);
+    apply_to_elements(vm, seq, &body);
+    Ok(())
+}
+
+// ( [ x1 ... xn ] [ pred ] -- [ ... ] ) The elements for which the
+// predicate leaves a true flag.
+fn bi_filter(vm: &mut Vm) -> Result<(), VmError> {
+    let (seq, pred) = pop_seq_and_body(vm, "filter", 0)?;
+    let depth = vm.stack.len();
+    call_then_resume(vm, &[], vec![Prim::Int(depth as i64)], gather_filter);
+    // Each element is pushed twice, so that `keep_if` still has it once
+    // the predicate has used up the other copy.
+    for element in seq.into_iter().rev() {
+        call_then_resume(vm, &pred, vec![], keep_if);
+        push_lit(vm, element.clone());
+        push_lit(vm, element);
+    }
+    Ok(())
+}
+
+// ( x flag -- x | ) After `filter` has called the predicate on x.
+fn keep_if(vm: &mut Vm) -> Result<(), VmError> {
+    if !pop_flag(&mut vm.stack, "filter")? {
+        vm.stack.pop();
+    }
+    Ok(())
+}
+
+fn gather_map(vm: &mut Vm) -> Result<(), VmError> {
+    gather(vm, "map")
+}
+
+fn gather_filter(vm: &mut Vm) -> Result<(), VmError> {
+    gather(vm, "filter")
+}
+
+// ( ... depth -- [ ... ] ) Gather everything above `depth` into a
+// quotation, once `map` or `filter` has been through the elements.
+fn gather(vm: &mut Vm, word: &str) -> Result<(), VmError> {
+    // The depth was pushed by `map` or `filter`, but it's still checked,
+    // like any other value: a builtin mustn't bring down the host.
+    require(&vm.stack, word, 1)?;
+    let depth = match &vm.stack[vm.stack.len() - 1] {
+        Prim::Int(depth) if *depth >= 0 => *depth as usize,
+        bad => return Err(type_mismatch(word, "stack depth", bad)),
+    };
+    vm.stack.pop();
+    if vm.stack.len() < depth {
+        return Err(VmError::StackUnderflow {
+            word: word.to_string(),
+            needed: depth,
+            found: vm.stack.len(),
+        });
+    }
+    let gathered = vm.stack.split_off(depth).into_iter()
+        .map(|prim| Atom::lit(prim, Loc::default()))
+        .collect();
+    vm.stack.push(Prim::Quot(gathered));
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+
+use sltf_parse::Loc;
+
+use super::Prim;
+
+
+// Everything that can go wrong while the vm is running. Builtins report
+// errors through these variants rather than panicking, so that a host
+// program (e.g. the repl) can recover and keep its word definitions.
+#[derive(Debug,PartialEq,Clone)]
+pub enum VmError {
+    // A word needed more values on the data stack than were available.
+    StackUnderflow { word: String, needed: usize, found: usize },
+    // Like StackUnderflow, but for the return stack.
+    ReturnStackUnderflow { word: String, needed: usize, found: usize },
+    // A word definition that left the return stack deeper or shallower
+    // than it found it.
+    ReturnStackImbalance { word: String, expected: usize, found: usize },
+    // A word got a value of the wrong type; `expected` describes what it wanted.
+    TypeMismatch { word: String, expected: &'static str, found: Prim },
+    // An int result that doesn't fit in an i64, under OverflowPolicy::Error.
+    IntegerOverflow(String),
+    // A division (or modulo) word was asked to divide by zero.
+    DivisionByZero(String),
+    // A symbol that isn't defined in the word map.
+    UnknownWord(String),
+    // A loop word (e.g. `i` or `leave`) used outside of a `do` loop.
+    NotInLoop(String),
+    // A word that works on the next atom of the program (e.g. `skip`)
+    // found no atom there.
+    NothingNext(String),
+    // Writing to the vm's output failed.
+    OutputFailed { word: String, message: String },
+    // `execute` was called with nothing left in the program.
+    EmptyProgram,
+    // Another error, tagged with the location of the atom that raised it.
+    At(Loc, Box<VmError>),
+}
+
+impl VmError {
+
+    // Tag the error with a location, unless it already has one.
+    pub fn at(self, loc: Loc) -> VmError {
+        match self {
+            VmError::At(..) => self,
+            other => VmError::At(loc, Box::new(other)),
+        }
+    }
+
+    // The underlying error, without any location.
+    pub fn root(&self) -> &VmError {
+        match self {
+            VmError::At(_, cause) => cause.root(),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { word, needed, found } =>
+                write!(f, "stack underflow in {:?}: needed {}, found {}", word, needed, found),
+            VmError::ReturnStackUnderflow { word, needed, found } =>
+                write!(f, "return stack underflow in {:?}: needed {}, found {}", word, needed, found),
+            VmError::ReturnStackImbalance { word, expected, found } =>
+                write!(f, "return stack imbalance at the end of {:?}: expected depth {}, found {}",
+                       word, expected, found),
+            VmError::TypeMismatch { word, expected, found } =>
+                write!(f, "type mismatch in {:?}: expected {}, found {:?}", word, expected, found),
+            VmError::IntegerOverflow(word) =>
+                write!(f, "integer overflow in {:?}", word),
+            VmError::DivisionByZero(word) =>
+                write!(f, "division by zero in {:?}", word),
+            VmError::UnknownWord(word) =>
+                write!(f, "undefined word {:?}", word),
+            VmError::NotInLoop(word) =>
+                write!(f, "{:?} used outside of a do loop", word),
+            VmError::NothingNext(word) =>
+                write!(f, "{:?} found no word after it in the program", word),
+            VmError::OutputFailed { word, message } =>
+                write!(f, "output failed in {:?}: {}", word, message),
+            VmError::EmptyProgram =>
+                write!(f, "nothing left to execute"),
+            VmError::At(loc, cause) => {
+                write!(f, "{} ({}", cause, loc)?;
+                if let Some(word) = &loc.word {
+                    write!(f, ", in definition of {:?}", word)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+impl Error for VmError {}
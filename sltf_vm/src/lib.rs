@@ -1,43 +1,182 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::rc::Rc;
 
 extern crate either;
 use either::{Either, Left, Right};
 
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive};
+
 #[cfg(test)] #[macro_use]
 extern crate pretty_assertions;
 
 extern crate sltf_parse;
 use sltf_parse::Ast;
 use sltf_parse::Atom;
+use sltf_parse::AtomKind;
+use sltf_parse::Loc;
 use sltf_parse::Prim;
 
 mod builtin_words;
+mod combinators;
+mod error;
+mod prog_words;
 
-// Note: I failed to figure out rust closures and gave up.
-//
-// The fn type is not a closure, but rather explicitly a static
-// function, which means it can be used as a stack value because it's
-// size (a pointer to the start instruction) is known at compile time.
-// This is not true of closures, which need to be boxed.
+pub use error::VmError;
+
+// Builtins get the whole vm, since some of them (e.g. `i` and `leave`)
+// need more than the data stack.
 //
-// I also failed to make the non-closure version work, because
-// in order to find the value of the word in WordMap, I had to look it
-// up. In the process, I have a borrow out on `vm`, which means I cannot
-// do anything with it.
+// A builtin is a closure, so that a host program can define words that
+// carry their own state (a counter, a database handle, ...). Closures
+// don't have a size known at compile time, so they live behind an `Rc`.
+// Looking a word up in the WordMap puts a borrow out on `vm`, but cloning
+// the `Rc` out of the map ends the borrow before we call it.
 //
-// This isn't actually a problem because I'm in tail position, meaning that
-// 
+// The vm's own builtins are plain fns, which is also what `Instr::Resume`
+// holds, since a fn is Copy and needs no allocation.
 type Stack = Vec<Prim>;
-type Prog = Vec<Ast>;
-type WordExec = fn(&mut Stack);
+type Prog = Vec<Instr>;
+type Builtin = fn(&mut Vm) -> Result<(), VmError>;
+pub type WordExec = Rc<dyn Fn(&mut Vm) -> Result<(), VmError>>;
 type WordBody = Vec<Atom>;
-type WordMap = HashMap<String, Either<WordExec, WordBody>>;
+type WordMap = HashMap<String, Either<WordExec, Definition>>;
+
+// A word defined with `:`, or with `::` for a program word. While a
+// program word runs, words like `skip` and `quote-next` work on the
+// program that follows its call, rather than on the rest of its body.
+struct Definition {
+    body: WordBody,
+    prog_word: bool,
+}
+
+// An item on the program stack. Besides the parsed program, the vm puts
+// its own markers here, e.g. at the end of a loop body so that it knows
+// when to go around again.
+enum Instr {
+    Ast(Ast),
+    // The end of one pass through the body of a `do` loop.
+    LoopEnd { body: Rc<[Atom]>, plus: bool, loc: Loc },
+    // The end of one pass through the body of a `begin ... until` loop.
+    UntilEnd { body: Rc<[Atom]>, loc: Loc },
+    // The end of the condition of a `begin ... while ... repeat` loop.
+    WhileTest { condition: Rc<[Atom]>, body: Rc<[Atom]>, loc: Loc },
+    // The end of the body of a user-defined word, with the depth of the
+    // return stack when the word was called. For a program word, the rest
+    // of the program after its call is whatever is below this marker.
+    WordEnd { name: String, depth: usize, prog_word: bool, loc: Loc },
+    // A builtin to carry on with once the code above it has run, e.g. so
+    // that `times` can go round again after calling its quotation.
+    Resume(Builtin),
+    // A value for the data stack that the vm keeps on the program stack
+    // for itself, e.g. the value `dip` restores once its quotation has run.
+    // Unlike a literal atom, it isn't part of the program that program
+    // words can see.
+    Push(Prim),
+}
+
+// What the arithmetic builtins do when a result doesn't fit in an i64.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum OverflowPolicy {
+    // Raise a VmError::IntegerOverflow.
+    #[default]
+    Error,
+    // Wrap around, two's complement style, like most Forths.
+    Wrap,
+    // Clamp to i64::MIN or i64::MAX.
+    Saturate,
+    // Switch to a Prim::BigInt, so that results are always exact.
+    Promote,
+}
+
+impl OverflowPolicy {
+
+    // Turn the exact result of an int operation into a Prim: a Prim::Int
+    // if it fits, and otherwise whatever the policy says.
+    pub fn narrow(self, word: &str, value: i128) -> Result<Prim, VmError> {
+        match i64::try_from(value) {
+            Ok(n) => Ok(Prim::Int(n)),
+            Err(_) => self.narrow_big(word, BigInt::from(value)),
+        }
+    }
+
+    // Like `narrow`, for results computed as big ints.
+    pub fn narrow_big(self, word: &str, value: BigInt) -> Result<Prim, VmError> {
+        if let Some(n) = value.to_i64() {
+            return Ok(Prim::Int(n));
+        }
+        match self {
+            OverflowPolicy::Error => Err(VmError::IntegerOverflow(word.to_string())),
+            OverflowPolicy::Wrap => {
+                let low_bits = (value & BigInt::from(u64::MAX)).to_u64()
+                    .expect("Masked to 64 bits");
+                Ok(Prim::Int(low_bits as i64))
+            },
+            OverflowPolicy::Saturate =>
+                Ok(Prim::Int(if value.is_negative() { i64::MIN } else { i64::MAX })),
+            OverflowPolicy::Promote => Ok(Prim::BigInt(value)),
+        }
+    }
+}
+
+// What `/` (and `*/`) do with two ints that don't divide evenly.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum IntDivision {
+    // Round the quotient towards negative infinity, like Forth.
+    #[default]
+    Floored,
+    // Give an exact Prim::Ratio.
+    Exact,
+}
+
+// How words like `=` and `true` represent their results. Conditionals
+// accept either kind of flag, whatever the style.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum FlagStyle {
+    // Like Forth: true is -1 (all bits set) and false is 0.
+    #[default]
+    Forth,
+    // Prim::Bool values.
+    Bool,
+}
+
+impl FlagStyle {
+
+    pub fn flag(self, value: bool) -> Prim {
+        match self {
+            FlagStyle::Forth => Prim::Int(if value { -1 } else { 0 }),
+            FlagStyle::Bool => Prim::Bool(value),
+        }
+    }
+}
+
+// The state of a running `do` loop.
+struct LoopFrame {
+    index: i64,
+    limit: i64,
+}
 
 
 pub struct Vm {
     pub stack: Stack,
+    // The return stack, for `>r`, `r>` and friends. Unlike in a real Forth
+    // it holds no return addresses: those are implicit in the program stack.
+    pub rstack: Stack,
     prog: Prog,
+    // One frame for each `do` loop we're inside of, innermost last.
+    loops: Vec<LoopFrame>,
     word_map: WordMap,
+    overflow_policy: OverflowPolicy,
+    flag_style: FlagStyle,
+    int_division: IntDivision,
+    // Where words like `.` print to.
+    output: Box<dyn Write>,
 }
 
 
@@ -45,88 +184,322 @@ pub struct Vm {
 impl Vm {
 
     pub fn new(prog_in: Vec<Ast>) -> Self {
-        let mut prog = prog_in;
-        prog.reverse();
-        Vm {
+        let mut vm = Vm {
             stack: Vec::new(),
-            prog,
-            word_map: builtin_words::initial_word_map(),
-        }
+            rstack: Vec::new(),
+            prog: Vec::new(),
+            loops: Vec::new(),
+            word_map: initial_word_map(),
+            overflow_policy: OverflowPolicy::default(),
+            flag_style: FlagStyle::default(),
+            int_division: IntDivision::default(),
+            output: Box::new(io::stdout()),
+        };
+        vm.new_input(prog_in);
+        vm
+    }
+
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    pub fn set_flag_style(&mut self, style: FlagStyle) {
+        self.flag_style = style;
+    }
+
+    pub fn set_int_division(&mut self, division: IntDivision) {
+        self.int_division = division;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Add a builtin word, or replace an existing word, e.g. so that a host
+    // program can provide words of its own.
+    pub fn define_builtin<F>(&mut self, name: &str, func: F)
+        where F: Fn(&mut Vm) -> Result<(), VmError> + 'static
+    {
+        self.word_map.insert(name.to_string(), Left(Rc::new(func)));
+    }
+
+    // Write some text to the vm's output; `word` is the word doing the
+    // writing, for errors.
+    pub fn write_output(&mut self, word: &str, text: &str) -> Result<(), VmError> {
+        self.output.write_all(text.as_bytes()).map_err(|err| VmError::OutputFailed {
+            word: word.to_string(),
+            message: err.to_string(),
+        })
     }
 
     pub fn new_input(&mut self, prog_in: Vec<Ast>) {
-        let mut prog = prog_in;
+        let mut prog: Prog = prog_in.into_iter().map(Instr::Ast).collect();
         prog.reverse();
         prog.append(&mut self.prog);
         self.prog = prog;
     }
 
+    // Throw away the rest of the program, e.g. after an error. Any loops
+    // we were in the middle of go with it, and so does the return stack.
+    pub fn clear_prog(&mut self) {
+        self.prog.clear();
+        self.loops.clear();
+        self.rstack.clear();
+    }
+
     pub fn finished(&self) -> bool {
-        self.prog.len() == 0
+        self.prog.is_empty()
     }
 
-    pub fn execute(&mut self) {
-        let item = self.prog.pop().expect("Called execute on vm with empty prog");
+    pub fn execute(&mut self) -> Result<(), VmError> {
+        let item = self.prog.pop().ok_or(VmError::EmptyProgram)?;
         match item {
-            Ast::Simple(atom) => {
-                self.execute_atom(atom)
+            Instr::Ast(Ast::Simple(atom)) => {
+                let loc = atom.loc.clone();
+                self.execute_atom(atom).map_err(|err| err.at(loc))
             }
-            Ast::WordDef(name, body) => {
-                let old = self.word_map.insert(
-                    name.clone(),
-                    Either::Right(body),
-                );
-                match old {
-                    Some(_) => println!("Redefined word {:?}", name),
-                    None => println!("Defined new word {:?}", name),
-                };
+            Instr::Ast(Ast::WordDef(name, body, _)) =>
+                self.define(name, Definition { body, prog_word: false }),
+            Instr::Ast(Ast::ProgWordDef(name, body, _)) =>
+                self.define(name, Definition { body, prog_word: true }),
+            Instr::LoopEnd { body, plus, loc } => {
+                self.loop_end(body, plus, loc.clone()).map_err(|err| err.at(loc))
+            }
+            Instr::UntilEnd { body, loc } => {
+                match builtin_words::pop_flag(&mut self.stack, "until") {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        self.prog.push(Instr::UntilEnd { body: body.clone(), loc });
+                        expand_atoms(&mut self.prog, &body);
+                        Ok(())
+                    },
+                    Err(err) => Err(err.at(loc)),
+                }
+            }
+            Instr::WhileTest { condition, body, loc } => {
+                match builtin_words::pop_flag(&mut self.stack, "while") {
+                    Ok(true) => {
+                        self.prog.push(Instr::WhileTest {
+                            condition: condition.clone(),
+                            body: body.clone(),
+                            loc,
+                        });
+                        expand_atoms(&mut self.prog, &condition);
+                        expand_atoms(&mut self.prog, &body);
+                        Ok(())
+                    },
+                    Ok(false) => Ok(()),
+                    Err(err) => Err(err.at(loc)),
+                }
+            }
+            Instr::Resume(func) => func(self),
+            Instr::Push(prim) => {
+                self.stack.push(prim);
+                Ok(())
+            }
+            Instr::WordEnd { name, depth, loc, .. } => {
+                if self.rstack.len() == depth {
+                    Ok(())
+                } else {
+                    let err = VmError::ReturnStackImbalance {
+                        word: name,
+                        expected: depth,
+                        found: self.rstack.len(),
+                    };
+                    Err(err.at(loc))
+                }
             }
         }
     }
 
 
-    fn execute_atom(&mut self, atom: Atom) {
-        match atom {
-            Atom::Lit(prim) => {
+    fn define(&mut self, name: String, definition: Definition) -> Result<(), VmError> {
+        let old = self.word_map.insert(name.clone(), Either::Right(definition));
+        let message = match old {
+            Some(_) => format!("Redefined word {:?}\n", name),
+            None => format!("Defined new word {:?}\n", name),
+        };
+        self.write_output(&name, &message)
+    }
+
+    // The index in `prog` just above the rest of the program after the
+    // innermost running program word, or the top of the program stack if
+    // no program word is running. Ordinary words don't count, so a
+    // program word can hand its work on to helper words.
+    fn prog_word_base(&self) -> usize {
+        self.prog.iter()
+            .rposition(|instr| matches!(instr, Instr::WordEnd { prog_word: true, .. }))
+            .unwrap_or(self.prog.len())
+    }
+
+    // The next atom of the program, as seen by a program word (see
+    // `prog_word_base`); `word` is the word that wants it, for errors.
+    // The vm's own markers, e.g. the end of a loop body, aren't atoms, so
+    // a program word can't see past them.
+    pub fn next_atom(&self, word: &str) -> Result<&Atom, VmError> {
+        match self.prog_word_base().checked_sub(1).map(|index| &self.prog[index]) {
+            Some(Instr::Ast(Ast::Simple(atom))) => Ok(atom),
+            _ => Err(VmError::NothingNext(word.to_string())),
+        }
+    }
+
+    // Like `next_atom`, but remove the atom from the program.
+    pub fn take_next_atom(&mut self, word: &str) -> Result<Atom, VmError> {
+        self.next_atom(word)?;
+        let index = self.prog_word_base() - 1;
+        match self.prog.remove(index) {
+            Instr::Ast(Ast::Simple(atom)) => Ok(atom),
+            _ => unreachable!("Checked for an atom above"),
+        }
+    }
+
+    // Put atoms into the program where `next_atom` looks, so that they run
+    // as soon as the innermost program word is finished.
+    pub fn inject(&mut self, atoms: &[Atom]) {
+        let base = self.prog_word_base();
+        let instrs: Vec<Instr> = atoms.iter().rev()
+            .map(|atom| Instr::Ast(Ast::Simple(atom.clone())))
+            .collect();
+        self.prog.splice(base..base, instrs);
+    }
+
+    fn execute_atom(&mut self, atom: Atom) -> Result<(), VmError> {
+        match atom.kind {
+            AtomKind::Lit(prim) => {
                 self.stack.push(prim);
+                Ok(())
             },
-            Atom::Symbol(word) => {
+            AtomKind::Symbol(word) => {
                 match self.word_map.get(&word) {
                     Some(Left(func)) => {
-                        func(&mut self.stack)
+                        let func = func.clone();
+                        func(self)
                     },
-                    Some(Right(body)) => {
-                        expand_word_def(&mut self.prog, &body);
+                    Some(Right(definition)) => {
+                        // The marker goes under the body, so that we can
+                        // check the return stack once the word is done.
+                        // A call in tail position, at the depth its caller
+                        // started at, shares the caller's marker instead,
+                        // so that tail recursion runs in constant space.
+                        // (An imbalance is then blamed on the caller.)
+                        let depth = self.rstack.len();
+                        let tail_call = !definition.prog_word && matches!(
+                            self.prog.last(),
+                            Some(Instr::WordEnd { depth: caller_depth, .. }) if *caller_depth == depth
+                        );
+                        if !tail_call {
+                            self.prog.push(Instr::WordEnd {
+                                name: word.clone(),
+                                depth,
+                                prog_word: definition.prog_word,
+                                loc: atom.loc,
+                            });
+                        }
+                        expand_atoms(&mut self.prog, &definition.body);
+                        Ok(())
                     },
                     None => {
-                        panic!("Could not find definition of word {:?}", &word);
+                        Err(VmError::UnknownWord(word))
                     },
                 }
             },
+            AtomKind::If(then_branch, else_branch) => {
+                // Rather than executing the branch here, we put it on the
+                // program stack, just like the body of a word.
+                let branch = if builtin_words::pop_flag(&mut self.stack, "if")? {
+                    then_branch
+                } else {
+                    else_branch
+                };
+                expand_atoms(&mut self.prog, &branch);
+                Ok(())
+            },
+            AtomKind::Do { body, conditional, plus } => {
+                let word = if conditional { "?do" } else { "do" };
+                let (limit, start) = builtin_words::pop_int_pair(&mut self.stack, word)?;
+                if conditional && start == limit {
+                    return Ok(());
+                }
+                self.loops.push(LoopFrame { index: start, limit });
+                let body: Rc<[Atom]> = Rc::from(body);
+                self.prog.push(Instr::LoopEnd { body: body.clone(), plus, loc: atom.loc });
+                expand_atoms(&mut self.prog, &body);
+                Ok(())
+            },
+            AtomKind::BeginUntil(body) => {
+                let body: Rc<[Atom]> = Rc::from(body);
+                self.prog.push(Instr::UntilEnd { body: body.clone(), loc: atom.loc });
+                expand_atoms(&mut self.prog, &body);
+                Ok(())
+            },
+            AtomKind::BeginWhile(condition, body) => {
+                let condition: Rc<[Atom]> = Rc::from(condition);
+                self.prog.push(Instr::WhileTest {
+                    condition: condition.clone(),
+                    body: Rc::from(body),
+                    loc: atom.loc,
+                });
+                expand_atoms(&mut self.prog, &condition);
+                Ok(())
+            },
+        }
+    }
+
+    // Step the innermost `do` loop, and go around again unless it's done.
+    fn loop_end(&mut self, body: Rc<[Atom]>, plus: bool, loc: Loc) -> Result<(), VmError> {
+        let word = if plus { "+loop" } else { "loop" };
+        let step = if plus { builtin_words::pop_int(&mut self.stack, word)? } else { 1 };
+        let done = {
+            let frame = self.loops.last_mut()
+                .ok_or_else(|| VmError::NotInLoop(word.to_string()))?;
+            // Like Forth, the loop ends when the index crosses the boundary
+            // between limit - 1 and limit, in either direction.
+            let before = i128::from(frame.index) - i128::from(frame.limit);
+            let after = before + i128::from(step);
+            frame.index = frame.index.wrapping_add(step);
+            (before < 0) != (after < 0)
         };
+        if done {
+            self.loops.pop();
+        } else {
+            self.prog.push(Instr::LoopEnd { body: body.clone(), plus, loc });
+            expand_atoms(&mut self.prog, &body);
+        }
+        Ok(())
     }
 
 }
 
-fn expand_word_def(prog: &mut Prog, body: &Vec<Atom>) {
+fn initial_word_map() -> WordMap {
+    let mut word_map = builtin_words::initial_word_map();
+    combinators::add_words(&mut word_map);
+    prog_words::add_words(&mut word_map);
+    word_map
+}
+
+// Put a sequence of atoms (e.g. a word body) on the program stack, so that
+// the first atom is the next to be executed. The copied atoms keep their
+// locations, so an error in the middle of a word can still be traced back
+// to the definition it came from.
+fn expand_atoms(prog: &mut Prog, body: &[Atom]) {
     for atom in body.iter().rev() {
         let copied = atom.clone();
-        prog.push(Ast::Simple(copied));
+        prog.push(Instr::Ast(Ast::Simple(copied)));
     };
 }
 
 #[test]
 fn vm_new_input_test() {
     let mut vm = Vm::new(vec![
-        Ast::Simple(Atom::Lit(Prim::Int(2))),
-        Ast::Simple(Atom::Lit(Prim::Int(3))),
+        Ast::Simple(Atom::lit(Prim::Int(2), Loc::default())),
+        Ast::Simple(Atom::lit(Prim::Int(3), Loc::default())),
     ]);
     vm.new_input(vec![
-        Ast::Simple(Atom::Lit(Prim::Int(4))),
-        Ast::Simple(Atom::Lit(Prim::Int(5))),
+        Ast::Simple(Atom::lit(Prim::Int(4), Loc::default())),
+        Ast::Simple(Atom::lit(Prim::Int(5), Loc::default())),
     ]);
     for _ in 0..4 {
-        vm.execute();
+        vm.execute().unwrap();
     }
     assert_eq!(vm.stack, vec![
         Prim::Int(2),
@@ -142,24 +515,30 @@ fn vm_new_input_test() {
 mod vm_execute_tests {
     use super::*;
 
+    // Quotations on the stack remember where they came from; the tests
+    // only care what they contain.
+    fn plain(stack: Vec<Prim>) -> Vec<Prim> {
+        stack.into_iter().map(Prim::without_locs).collect()
+    }
+
     fn _tcase(prog: Vec<Ast>,
               n_executions: Option<u64>,
               expected_stack: Vec<Prim>) {
         let mut vm = Vm::new(prog);
         match n_executions {
             None => {
-                while vm.prog.len() > 0 {
-                    vm.execute()
+                while !vm.finished() {
+                    vm.execute().unwrap()
                 }
             },
             Some(n) => {
                 for _ in 0..n {
-                    vm.execute()
+                    vm.execute().unwrap()
                 }
             },
         };
         println!("stack: {:?}", &vm.stack);
-        assert_eq!(vm.stack, expected_stack);
+        assert_eq!(plain(vm.stack), expected_stack);
     }
 
     fn tcase_run_all(prog: Vec<Ast>,
@@ -173,12 +552,37 @@ mod vm_execute_tests {
         _tcase(prog, Some(n), expected_stack);
     }
 
+    // Run until the vm reports an error, which must be `expected_error`.
+    fn tcase_error(prog: Vec<Ast>,
+                   expected_error: VmError,
+                   expected_stack: Vec<Prim>) {
+        let mut vm = Vm::new(prog);
+        let actual_error = loop {
+            match vm.execute() {
+                Ok(()) => assert!(!vm.finished(), "expected an error, got none"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(actual_error.root(), &expected_error);
+        assert_eq!(plain(vm.stack), expected_stack);
+    }
+
+    fn tcase_source(source: &str,
+                    expected_stack: Vec<Prim>) {
+        let prog = sltf_parse::Parser::new().parse(source).unwrap();
+        tcase_run_all(prog, expected_stack);
+    }
+
+    fn ints(values: &[i64]) -> Vec<Prim> {
+        values.iter().map(|n| Prim::Int(*n)).collect()
+    }
+
     fn simple_lit(prim: Prim) -> Ast {
-        Ast::Simple(Atom::Lit(prim))
+        Ast::Simple(Atom::lit(prim, Loc::default()))
     }
 
     fn simple_sym(name: &str) -> Ast {
-        Ast::Simple(Atom::Symbol(name.to_string()))
+        Ast::Simple(Atom::symbol(name, Loc::default()))
     }
 
     #[test]
@@ -225,6 +629,114 @@ mod vm_execute_tests {
         );
     }
 
+    #[test]
+    fn test_integer_arith() {
+        tcase_source("7 10 -", ints(&[-3]));
+        tcase_source("7 2 / 7 2 mod 7 2 /mod", ints(&[3, 1, 1, 3]));
+        tcase_source("-7 2 / -7 2 mod -7 2 /mod", ints(&[-4, 1, 1, -4]));
+        tcase_source("7 -2 / 7 -2 mod -7 -2 /mod", ints(&[-4, -1, -1, 3]));
+        // `fm/mod` is `/mod` by its standard name; `sm/rem` rounds the
+        // quotient towards zero, so the remainder takes the dividend's sign.
+        tcase_source("-7 2 fm/mod 7 -2 fm/mod -7 -2 fm/mod", ints(&[1, -4, -1, -4, -1, 3]));
+        tcase_source("-7 2 sm/rem 7 -2 sm/rem -7 -2 sm/rem 7 2 sm/rem", ints(&[-1, -3, 1, -3, -1, 3, 1, 3]));
+        tcase_source("5 negate -5 abs 3 -4 min 3 -4 max", ints(&[-5, 5, -4, 3]));
+        tcase_source("1000000000000 3000000 1000000 */ -7 1 2 */", ints(&[3000000000000, -4]));
+    }
+
+    fn tcase_policy(policy: OverflowPolicy,
+                    source: &str,
+                    expected: Result<Vec<Prim>, VmError>) {
+        tcase_configured(|vm| vm.set_overflow_policy(policy), source, expected);
+    }
+
+    // Run `source` on a vm set up by `configure`, until it finishes or fails.
+    fn tcase_configured<F: Fn(&mut Vm)>(configure: F,
+                                        source: &str,
+                                        expected: Result<Vec<Prim>, VmError>) {
+        let prog = sltf_parse::Parser::new().parse(source).unwrap();
+        let mut vm = Vm::new(prog);
+        configure(&mut vm);
+        let mut result = Ok(());
+        while result.is_ok() && !vm.finished() {
+            result = vm.execute();
+        }
+        match result {
+            Ok(()) => assert_eq!(Ok(plain(vm.stack)), expected),
+            Err(err) => assert_eq!(Err(err.root().clone()), expected),
+        }
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let max = i64::MAX;
+        let min = i64::MIN;
+        let overflow = |word: &str| Err(VmError::IntegerOverflow(word.to_string()));
+        // (source, word that overflows, wrapped result, saturated result)
+        let cases = vec![
+            ("9223372036854775807 1 +", "+", min, max),
+            ("-9223372036854775808 1 -", "-", max, min),
+            ("4611686018427387904 -4 *", "*", 0, min),
+            ("-9223372036854775808 -1 /", "/", min, max),
+            ("-9223372036854775808 negate", "negate", min, max),
+            ("-9223372036854775808 abs", "abs", min, max),
+            ("9223372036854775807 3 2 */", "*/", -4611686018427387906, max),
+        ];
+        for (source, word, wrapped, saturated) in cases {
+            tcase_policy(OverflowPolicy::Error, source, overflow(word));
+            tcase_policy(OverflowPolicy::Wrap, source, Ok(ints(&[wrapped])));
+            tcase_policy(OverflowPolicy::Saturate, source, Ok(ints(&[saturated])));
+        }
+        tcase_policy(OverflowPolicy::Error, "-9223372036854775808 -1 /mod", overflow("/mod"));
+        tcase_policy(OverflowPolicy::Wrap, "-9223372036854775808 -1 /mod", Ok(ints(&[0, min])));
+        tcase_policy(OverflowPolicy::Error, "-9223372036854775808 -1 mod", Ok(ints(&[0])));
+    }
+
+    #[test]
+    fn test_overflow_leaves_operands() {
+        tcase_error(
+            sltf_parse::Parser::new().parse("7 9223372036854775807 1 +").unwrap(),
+            VmError::IntegerOverflow("+".to_string()),
+            ints(&[7, i64::MAX, 1]),
+        );
+        tcase_error(
+            sltf_parse::Parser::new().parse("-9223372036854775808 -1 /mod").unwrap(),
+            VmError::IntegerOverflow("/mod".to_string()),
+            ints(&[i64::MIN, -1]),
+        );
+    }
+
+    #[test]
+    fn test_integer_arith_digit_words() {
+        tcase_source("5 1+ 5 1- 5 2* -5 2/", ints(&[6, 4, 10, -3]));
+        tcase_source(": 2dup over over ; : 3+ 3 + ; 1 2 2dup 3+", ints(&[1, 2, 1, 5]));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        for word in &["/", "mod", "/mod", "fm/mod", "sm/rem"] {
+            tcase_error(
+                source(&format!("1 0 {}", word)),
+                VmError::DivisionByZero(word.to_string()),
+                ints(&[1, 0]),
+            );
+        }
+        tcase_error(
+            source("2 3 0 */"),
+            VmError::DivisionByZero("*/".to_string()),
+            ints(&[2, 3, 0]),
+        );
+        tcase_error(
+            source("\"x\" 3 4 */"),
+            VmError::TypeMismatch {
+                word: "*/".to_string(),
+                expected: "int",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![Prim::Str("x".to_string()), Prim::Int(3), Prim::Int(4)],
+        );
+    }
+
     #[test]
     fn test_stack_dup_fns() {
         tcase_run_all(
@@ -244,6 +756,184 @@ mod vm_execute_tests {
         );
     }
 
+    #[test]
+    fn test_stack_shuffle_fns() {
+        tcase_run_all(
+            vec![
+                simple_lit(Prim::Int(1)),
+                simple_lit(Prim::Int(2)),
+                simple_sym("over"),
+                simple_lit(Prim::Int(3)),
+                simple_sym("rot"),
+                simple_sym("-rot"),
+                simple_sym("-rot"),
+            ],
+            vec![
+                Prim::Int(1),
+                Prim::Int(3),
+                Prim::Int(2),
+                Prim::Int(1),
+            ],
+        );
+        tcase_run_all(
+            vec![
+                simple_lit(Prim::Int(1)),
+                simple_lit(Prim::Int(2)),
+                simple_lit(Prim::Int(3)),
+                simple_sym("nip"),
+                simple_sym("tuck"),
+            ],
+            vec![
+                Prim::Int(3),
+                Prim::Int(1),
+                Prim::Int(3),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_stack_pick_roll() {
+        tcase_run_all(
+            vec![
+                simple_lit(Prim::Int(10)),
+                simple_lit(Prim::Int(11)),
+                simple_lit(Prim::Int(12)),
+                simple_lit(Prim::Int(2)),
+                simple_sym("pick"),
+                simple_lit(Prim::Int(0)),
+                simple_sym("pick"),
+            ],
+            vec![
+                Prim::Int(10),
+                Prim::Int(11),
+                Prim::Int(12),
+                Prim::Int(10),
+                Prim::Int(10),
+            ],
+        );
+        tcase_run_all(
+            vec![
+                simple_lit(Prim::Int(10)),
+                simple_lit(Prim::Int(11)),
+                simple_lit(Prim::Int(12)),
+                simple_lit(Prim::Int(2)),
+                simple_sym("roll"),
+                simple_lit(Prim::Int(0)),
+                simple_sym("roll"),
+            ],
+            vec![
+                Prim::Int(11),
+                Prim::Int(12),
+                Prim::Int(10),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_stack_pair_fns() {
+        tcase_run_all(
+            vec![
+                simple_lit(Prim::Int(1)),
+                simple_lit(Prim::Int(2)),
+                simple_lit(Prim::Int(3)),
+                simple_lit(Prim::Int(4)),
+                simple_sym("2over"),
+                simple_sym("2swap"),
+                simple_lit(Prim::Int(5)),
+                simple_lit(Prim::Int(6)),
+                simple_sym("2drop"),
+            ],
+            vec![
+                Prim::Int(1),
+                Prim::Int(2),
+                Prim::Int(1),
+                Prim::Int(2),
+                Prim::Int(3),
+                Prim::Int(4),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_stack_question_dup_depth() {
+        tcase_run_all(
+            vec![
+                simple_sym("depth"),
+                simple_sym("?dup"),
+                simple_lit(Prim::Int(7)),
+                simple_sym("?dup"),
+                simple_sym("depth"),
+            ],
+            vec![
+                Prim::Int(0),
+                Prim::Int(7),
+                Prim::Int(7),
+                Prim::Int(3),
+            ],
+        );
+        // Any kind of flag will do, as for a conditional.
+        tcase_source("0.0 ?dup 0.5 ?dup 0 1/2 ?dup", vec![
+            Prim::Float(0.0), Prim::Float(0.5), Prim::Float(0.5),
+            Prim::Int(0), ratio(1, 2), ratio(1, 2),
+        ]);
+        tcase_configured(|vm| vm.set_flag_style(FlagStyle::Bool),
+                         "false ?dup true ?dup", Ok(vec![Prim::Bool(false), Prim::Bool(true), Prim::Bool(true)]));
+        tcase_error(
+            vec![simple_lit(Prim::Str("x".to_string())), simple_sym("?dup")],
+            VmError::TypeMismatch {
+                word: "?dup".to_string(),
+                expected: "flag",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![Prim::Str("x".to_string())],
+        );
+    }
+
+    #[test]
+    fn test_stack_shuffle_underflow() {
+        let cases: Vec<(&str, usize)> = vec![
+            ("over", 2), ("rot", 3), ("-rot", 3), ("nip", 2), ("tuck", 2),
+            ("2drop", 2), ("2swap", 4), ("2over", 4), ("?dup", 1),
+        ];
+        for (word, needed) in cases {
+            tcase_error(
+                vec![simple_sym(word)],
+                VmError::StackUnderflow { word: word.to_string(), needed, found: 0 },
+                vec![],
+            );
+        }
+        for word in &["pick", "roll"] {
+            tcase_error(
+                vec![
+                    simple_lit(Prim::Int(10)),
+                    simple_lit(Prim::Int(1)),
+                    simple_sym(word),
+                ],
+                VmError::StackUnderflow { word: word.to_string(), needed: 3, found: 2 },
+                vec![
+                    Prim::Int(10),
+                    Prim::Int(1),
+                ],
+            );
+            tcase_error(
+                vec![
+                    simple_lit(Prim::Int(10)),
+                    simple_lit(Prim::Int(-1)),
+                    simple_sym(word),
+                ],
+                VmError::TypeMismatch {
+                    word: word.to_string(),
+                    expected: "non-negative int",
+                    found: Prim::Int(-1),
+                },
+                vec![
+                    Prim::Int(10),
+                    Prim::Int(-1),
+                ],
+            );
+        }
+    }
+
     #[test]
     fn test_swap() {
         tcase_run_all(
@@ -258,5 +948,856 @@ mod vm_execute_tests {
             ],
         );
     }
-}
 
+    #[test]
+    fn test_run_partial() {
+        tcase_run_n(
+            vec![
+                simple_lit(Prim::Int(2)),
+                simple_lit(Prim::Int(3)),
+                simple_sym("+"),
+            ],
+            vec![
+                Prim::Int(2),
+                Prim::Int(3),
+            ],
+            2,
+        );
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        tcase_error(
+            vec![
+                simple_lit(Prim::Int(2)),
+                simple_sym("swap"),
+            ],
+            VmError::StackUnderflow {
+                word: "swap".to_string(),
+                needed: 2,
+                found: 1,
+            },
+            vec![
+                Prim::Int(2),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        tcase_error(
+            vec![
+                simple_lit(Prim::Str("two".to_string())),
+                simple_lit(Prim::Int(3)),
+                simple_sym("*"),
+            ],
+            VmError::TypeMismatch {
+                word: "*".to_string(),
+                expected: "number",
+                found: Prim::Str("two".to_string()),
+            },
+            vec![
+                Prim::Str("two".to_string()),
+                Prim::Int(3),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_unknown_word() {
+        tcase_error(
+            vec![
+                simple_lit(Prim::Int(2)),
+                simple_sym("frobnicate"),
+            ],
+            VmError::UnknownWord("frobnicate".to_string()),
+            vec![
+                Prim::Int(2),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let mut vm = Vm::new(vec![]);
+        assert_eq!(vm.execute(), Err(VmError::EmptyProgram));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let parser = sltf_parse::Parser::new();
+        let prog = parser.parse(": square\n  dup * ;\nsquare").unwrap();
+        let mut vm = Vm::new(prog);
+        vm.execute().unwrap();
+        vm.execute().unwrap();
+        match vm.execute() {
+            Err(VmError::At(loc, cause)) => {
+                assert_eq!(*cause, VmError::StackUnderflow {
+                    word: "dup".to_string(),
+                    needed: 1,
+                    found: 0,
+                });
+                assert_eq!((loc.line, loc.column), (2, 3));
+                assert_eq!(loc.word.as_ref().map(|word| word.as_ref()), Some("square"));
+            },
+            other => panic!("Expected a located error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_if_else() {
+        tcase_source("
+            : sign dup 0 < if drop -1 else 0 > if 1 else 0 then then ;
+            -7 sign 0 sign 12 sign
+            5 5 = if \"same\" then
+            1 2 <> if 3 else 4 then
+        ", vec![
+            Prim::Int(-1),
+            Prim::Int(0),
+            Prim::Int(1),
+            Prim::Str("same".to_string()),
+            Prim::Int(3),
+        ]);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        tcase_source("
+            1 2 < 2 1 < 2 2 <= 3 2 >= 3 4 > \"a\" \"a\" = \"a\" 1 =
+        ", vec![
+            Prim::Int(-1),
+            Prim::Int(0),
+            Prim::Int(-1),
+            Prim::Int(-1),
+            Prim::Int(0),
+            Prim::Int(-1),
+            Prim::Int(0),
+        ]);
+    }
+
+    #[test]
+    fn test_if_errors() {
+        tcase_error(
+            vec![
+                Ast::Simple(Atom::new(AtomKind::If(vec![], vec![]), Loc::default())),
+            ],
+            VmError::StackUnderflow {
+                word: "if".to_string(),
+                needed: 1,
+                found: 0,
+            },
+            vec![],
+        );
+        tcase_error(
+            vec![
+                simple_lit(Prim::Str("yes".to_string())),
+                Ast::Simple(Atom::new(AtomKind::If(vec![], vec![]), Loc::default())),
+            ],
+            VmError::TypeMismatch {
+                word: "if".to_string(),
+                expected: "flag",
+                found: Prim::Str("yes".to_string()),
+            },
+            vec![
+                Prim::Str("yes".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_do_loop() {
+        tcase_source("0 10 0 do i + loop", ints(&[45]));
+        tcase_source("3 0 do 2 0 do j 10 * i + loop loop", ints(&[0, 1, 10, 11, 20, 21]));
+        tcase_source(": countdown 0 9 do i -3 +loop ; countdown", ints(&[9, 6, 3, 0]));
+        tcase_source("10 0 do i 4 +loop", ints(&[0, 4, 8]));
+        tcase_source("7 5 5 ?do 1 loop", ints(&[7]));
+        tcase_source("5 5 ?do 1 leave loop 6 4 ?do i loop", ints(&[4, 5]));
+    }
+
+    #[test]
+    fn test_leave() {
+        tcase_source("10 0 do i dup 3 = if leave then loop", ints(&[0, 1, 2, 3]));
+        tcase_source(
+            "3 0 do 10 0 do i j = if leave then i loop 100 loop",
+            ints(&[100, 0, 100, 0, 1, 100]),
+        );
+        tcase_source(": stop leave ; 10 0 do i dup 2 = if stop then loop 99", ints(&[0, 1, 2, 99]));
+    }
+
+    #[test]
+    fn test_begin_loops() {
+        tcase_source("0 begin 1 + dup 5 = until", ints(&[5]));
+        tcase_source("5 begin dup while dup -1 + repeat", ints(&[5, 4, 3, 2, 1, 0]));
+        tcase_source("0 begin 0 while 1 repeat", ints(&[0]));
+    }
+
+    #[test]
+    fn test_loop_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("1 i"),
+            VmError::NotInLoop("i".to_string()),
+            ints(&[1]),
+        );
+        tcase_error(
+            source("2 0 do i j loop"),
+            VmError::NotInLoop("j".to_string()),
+            ints(&[0]),
+        );
+        tcase_error(
+            source("2 0 do \"x\" +loop"),
+            VmError::TypeMismatch {
+                word: "+loop".to_string(),
+                expected: "int",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![Prim::Str("x".to_string())],
+        );
+        tcase_error(
+            source("0 begin until"),
+            VmError::StackUnderflow {
+                word: "until".to_string(),
+                needed: 1,
+                found: 0,
+            },
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_clear_prog_ends_loops() {
+        let prog = sltf_parse::Parser::new().parse("10 0 do i loop").unwrap();
+        let mut vm = Vm::new(prog);
+        for _ in 0..4 {
+            vm.execute().unwrap();
+        }
+        vm.clear_prog();
+        assert!(vm.finished());
+        assert!(vm.loops.is_empty());
+    }
+
+    #[test]
+    fn test_return_stack() {
+        tcase_source("1 2 >r 3 r@ r>", ints(&[1, 3, 2, 2]));
+        tcase_source("1 2 >r >r r> r> 5 >r rdrop", ints(&[1, 2]));
+        tcase_source("1 2 3 2>r 4 2r>", ints(&[1, 4, 2, 3]));
+        tcase_source(": rot3 >r swap r> swap ; 1 2 3 rot3", ints(&[2, 3, 1]));
+        tcase_source(": sum-to 0 swap 0 do >r i r> + loop ; 5 sum-to", ints(&[10]));
+    }
+
+    #[test]
+    fn test_return_stack_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("1 r>"),
+            VmError::ReturnStackUnderflow {
+                word: "r>".to_string(),
+                needed: 1,
+                found: 0,
+            },
+            ints(&[1]),
+        );
+        tcase_error(
+            source("1 >r 2r>"),
+            VmError::ReturnStackUnderflow {
+                word: "2r>".to_string(),
+                needed: 2,
+                found: 1,
+            },
+            vec![],
+        );
+        tcase_error(
+            source(": stash >r ; 1 2 stash"),
+            VmError::ReturnStackImbalance {
+                word: "stash".to_string(),
+                expected: 0,
+                found: 1,
+            },
+            ints(&[1]),
+        );
+        // A word may use values its caller put on the return stack, as
+        // long as it leaves the depth the way it found it.
+        tcase_error(
+            source(": grab r> ; 1 >r grab"),
+            VmError::ReturnStackImbalance {
+                word: "grab".to_string(),
+                expected: 1,
+                found: 0,
+            },
+            ints(&[1]),
+        );
+        tcase_source(": swap-r r> r> swap >r >r ; 1 >r 2 >r swap-r r> r>", ints(&[1, 2]));
+        // Leaving a loop from inside a word still ends the word.
+        tcase_error(
+            source(": r-leave >r leave ; 10 0 do i 2 = if 7 r-leave then loop"),
+            VmError::ReturnStackImbalance {
+                word: "r-leave".to_string(),
+                expected: 0,
+                found: 1,
+            },
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_space() {
+        let prog = sltf_parse::Parser::new()
+            .parse(": down dup 0 > if 1 - down then ; 1000 down")
+            .unwrap();
+        let mut vm = Vm::new(prog);
+        let mut longest = 0;
+        while !vm.finished() {
+            vm.execute().unwrap();
+            longest = longest.max(vm.prog.len());
+        }
+        assert_eq!(vm.stack, ints(&[0]));
+        assert!(longest < 10, "the program stack grew to {}", longest);
+    }
+
+    #[test]
+    fn test_clear_prog_clears_return_stack() {
+        let prog = sltf_parse::Parser::new().parse("1 >r").unwrap();
+        let mut vm = Vm::new(prog);
+        vm.execute().unwrap();
+        vm.execute().unwrap();
+        assert_eq!(vm.rstack, ints(&[1]));
+        vm.clear_prog();
+        assert!(vm.rstack.is_empty());
+    }
+
+    #[test]
+    fn test_zero_comparisons() {
+        tcase_source("0 0= 5 0= -5 0< 5 0<", ints(&[-1, 0, -1, 0]));
+    }
+
+    #[test]
+    fn test_logic_on_forth_flags() {
+        tcase_source("true false true false", ints(&[-1, 0, -1, 0]));
+        tcase_source("1 2 < 3 4 < and 1 2 > 3 4 < or 1 2 < 3 4 < xor", ints(&[-1, -1, 0]));
+        tcase_source("12 10 and 12 10 or 12 10 xor 0 invert 5 invert", ints(&[8, 14, 6, -1, -6]));
+        tcase_source("1 2 = invert if 10 else 20 then", ints(&[10]));
+    }
+
+    #[test]
+    fn test_bool_flags() {
+        let bools = |values: &[bool]| values.iter().map(|b| Prim::Bool(*b)).collect::<Vec<Prim>>();
+        let bool_style = |vm: &mut Vm| vm.set_flag_style(FlagStyle::Bool);
+        tcase_configured(bool_style, "true false 1 1 = 1 2 < 2 1 <", Ok(bools(&[true, false, true, true, false])));
+        tcase_configured(bool_style, "0 0= 1 0< true 0=", Ok(bools(&[true, false, false])));
+        tcase_configured(bool_style, "true false and true false or true true xor false invert",
+                         Ok(bools(&[false, true, false, true])));
+        tcase_configured(bool_style, "1 2 < if 10 then 2 1 < if 20 else 30 then", Ok(ints(&[10, 30])));
+        tcase_configured(bool_style, "0 begin 1 + dup 3 = until", Ok(ints(&[3])));
+        // Int flags still work in conditionals, and ints are still bitwise.
+        tcase_configured(bool_style, "-1 if 1 then 12 10 and", Ok(ints(&[1, 8])));
+        // So do other numbers, with the same rule: anything nonzero is true.
+        tcase_source("0.0 if 1 then 0.5 if 2 then 1/2 if 3 then", ints(&[2, 3]));
+    }
+
+    #[test]
+    fn test_logic_errors() {
+        let bool_style = |vm: &mut Vm| vm.set_flag_style(FlagStyle::Bool);
+        tcase_configured(bool_style, "true 1 and", Err(VmError::TypeMismatch {
+            word: "and".to_string(),
+            expected: "bool",
+            found: Prim::Int(1),
+        }));
+        tcase_configured(bool_style, "1 true or", Err(VmError::TypeMismatch {
+            word: "or".to_string(),
+            expected: "int",
+            found: Prim::Bool(true),
+        }));
+        tcase_error(
+            sltf_parse::Parser::new().parse("\"a\" invert").unwrap(),
+            VmError::TypeMismatch {
+                word: "invert".to_string(),
+                expected: "int or bool",
+                found: Prim::Str("a".to_string()),
+            },
+            vec![Prim::Str("a".to_string())],
+        );
+    }
+
+    #[test]
+    fn test_shifts() {
+        tcase_source("1 4 lshift 255 4 rshift -16 2 arshift", ints(&[16, 15, -4]));
+        tcase_source("-1 60 rshift -1 63 lshift", ints(&[15, i64::MIN]));
+        tcase_source("1 0 lshift 1 64 lshift -1 64 rshift 5 100 arshift -5 100 arshift",
+                     ints(&[1, 0, 0, 0, -1]));
+        tcase_source("0 popcount 255 popcount -1 popcount", ints(&[0, 8, 64]));
+    }
+
+    #[test]
+    fn test_shift_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("1 -1 lshift"),
+            VmError::TypeMismatch {
+                word: "lshift".to_string(),
+                expected: "non-negative int",
+                found: Prim::Int(-1),
+            },
+            ints(&[1, -1]),
+        );
+        tcase_error(
+            source("\"a\" 1 rshift"),
+            VmError::TypeMismatch {
+                word: "rshift".to_string(),
+                expected: "int",
+                found: Prim::Str("a".to_string()),
+            },
+            vec![Prim::Str("a".to_string()), Prim::Int(1)],
+        );
+    }
+
+    fn floats(values: &[f64]) -> Vec<Prim> {
+        values.iter().map(|x| Prim::Float(*x)).collect()
+    }
+
+    #[test]
+    fn test_float_arith() {
+        tcase_source("1.5 2.25 + 1.5 2 * 1 0.5 - 7 2.0 /", floats(&[3.75, 3.0, 0.5, 3.5]));
+        tcase_source("-1.5 abs -1.5 negate 2 1.5 min 2 1.5 max", floats(&[1.5, 1.5, 1.5, 2.0]));
+        tcase_source("1 0.0 / -1 0.0 /", floats(&[f64::INFINITY, f64::NEG_INFINITY]));
+        // Ints stay ints unless a float is involved.
+        tcase_source("7 2 / 2 3 min", ints(&[3, 2]));
+    }
+
+    #[test]
+    fn test_float_comparisons() {
+        tcase_source("1 1.0 = 1.5 1 > 1.5 2 < 2.0 2 <= 1 0.5 >= 1 1.0 <>", ints(&[-1, -1, -1, -1, -1, 0]));
+        tcase_source("nan nan = nan 1 < nan 1 >= nan nan <>", ints(&[0, 0, 0, -1]));
+        tcase_source("0.0 0= -0.0 0= 0.5 0= -0.5 0<", ints(&[-1, -1, 0, -1]));
+    }
+
+    #[test]
+    fn test_float_words() {
+        tcase_source("16 sqrt 0 sin 0 cos 0 exp 1 ln", floats(&[4.0, 0.0, 1.0, 1.0, 0.0]));
+        tcase_source("2.7 floor -2.5 round 2.5 round -2.7 floor", floats(&[2.0, -3.0, 3.0, -3.0]));
+        tcase_source("3 s>f 2.9 f>s -2.9 f>s", vec![Prim::Float(3.0), Prim::Int(2), Prim::Int(-2)]);
+        tcase_source("1.5 2 f.", floats(&[1.5]));
+    }
+
+    #[test]
+    fn test_float_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("inf f>s"),
+            VmError::TypeMismatch {
+                word: "f>s".to_string(),
+                expected: "finite float",
+                found: Prim::Float(f64::INFINITY),
+            },
+            floats(&[f64::INFINITY]),
+        );
+        tcase_error(
+            source("1e30 f>s"),
+            VmError::IntegerOverflow("f>s".to_string()),
+            floats(&[1e30]),
+        );
+        tcase_error(
+            source("1.5 2 mod"),
+            VmError::TypeMismatch {
+                word: "mod".to_string(),
+                expected: "int",
+                found: Prim::Float(1.5),
+            },
+            vec![Prim::Float(1.5), Prim::Int(2)],
+        );
+        tcase_error(
+            source("\"x\" sqrt"),
+            VmError::TypeMismatch {
+                word: "sqrt".to_string(),
+                expected: "number",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![Prim::Str("x".to_string())],
+        );
+        tcase_policy(OverflowPolicy::Saturate, "1e30 f>s", Ok(ints(&[i64::MAX])));
+    }
+
+    fn big(digits: &str) -> Prim {
+        Prim::BigInt(digits.parse().unwrap())
+    }
+
+    #[test]
+    fn test_promote_to_big_ints() {
+        let promote = |source: &str, expected: Vec<Prim>| {
+            tcase_policy(OverflowPolicy::Promote, source, Ok(expected));
+        };
+        promote(": fact 1 swap 1 + 1 ?do i * loop ; 25 fact", vec![big("15511210043330985984000000")]);
+        promote("9223372036854775807 1 + -9223372036854775808 1 -",
+                vec![big("9223372036854775808"), big("-9223372036854775809")]);
+        promote("-9223372036854775808 negate -9223372036854775808 abs -9223372036854775808 -1 /",
+                vec![big("9223372036854775808"); 3]);
+        promote("1e20 f>s", vec![big("100000000000000000000")]);
+    }
+
+    #[test]
+    fn test_big_ints_demote() {
+        let promote = |source: &str, expected: Vec<Prim>| {
+            tcase_policy(OverflowPolicy::Promote, source, Ok(expected));
+        };
+        promote("9223372036854775807 1 + 1 -", ints(&[i64::MAX]));
+        promote("9223372036854775807 dup * 9223372036854775807 /", ints(&[i64::MAX]));
+        promote("9223372036854775807 dup * 10 mod 9223372036854775807 dup * 10 /mod drop",
+                ints(&[9, 9]));
+        promote("9223372036854775807 1 + 2 / 9223372036854775807 1 + 9223372036854775807 min",
+                ints(&[4611686018427387904, i64::MAX]));
+        promote("9223372036854775807 3 3 */", ints(&[i64::MAX]));
+        promote("-9223372036854775808 7 * 10 sm/rem -9223372036854775808 7 * 10 fm/mod",
+                ints(&[-6, -6456360425798343065, 4, -6456360425798343066]));
+    }
+
+    #[test]
+    fn test_big_ints_mixed() {
+        let promote = |source: &str, expected: Vec<Prim>| {
+            tcase_policy(OverflowPolicy::Promote, source, Ok(expected));
+        };
+        promote("9223372036854775807 1 + dup 0 > swap 9223372036854775807 =", ints(&[-1, 0]));
+        promote("9223372036854775807 1 + 1.0 *", vec![Prim::Float(9223372036854775808.0)]);
+        tcase_policy(OverflowPolicy::Promote, "9223372036854775807 1 + 0 /",
+                     Err(VmError::DivisionByZero("/".to_string())));
+        tcase_policy(OverflowPolicy::Promote, "9223372036854775807 1 + 1 lshift",
+                     Err(VmError::TypeMismatch {
+                         word: "lshift".to_string(),
+                         expected: "int",
+                         found: big("9223372036854775808"),
+                     }));
+    }
+
+    fn ratio(n: i64, d: i64) -> Prim {
+        Prim::Ratio(num_rational::BigRational::new(n.into(), d.into()))
+    }
+
+    fn tcase_exact(source: &str, expected: Result<Vec<Prim>, VmError>) {
+        tcase_configured(|vm| vm.set_int_division(IntDivision::Exact), source, expected);
+    }
+
+    #[test]
+    fn test_ratio_arith() {
+        tcase_source("1/3 1/6 +", vec![ratio(1, 2)]);
+        tcase_source("1/2 2 *", ints(&[1]));
+        tcase_source("1/3 1 -", vec![ratio(-2, 3)]);
+        tcase_source("2/3 1/3 /", ints(&[2]));
+        tcase_source("-1/2 abs 1/3 negate", vec![ratio(1, 2), ratio(-1, 3)]);
+        tcase_source("1/2 1/3 min 1/2 1/3 max", vec![ratio(1, 3), ratio(1, 2)]);
+        tcase_source("1/2 0.25 +", floats(&[0.75]));
+        tcase_source("1/3 1/3 = 1/3 1/2 < 2/4 1/2 = 1 1/2 >", ints(&[-1, -1, -1, -1]));
+        tcase_source("1 3 /", ints(&[0]));
+        tcase_source("1 3 ratio 6 4 ratio 1/2 1/4 ratio", vec![ratio(1, 3), ratio(3, 2), Prim::Int(2)]);
+    }
+
+    #[test]
+    fn test_exact_division() {
+        let exact = |source: &str, expected: Vec<Prim>| tcase_exact(source, Ok(expected));
+        exact("1 3 /", vec![ratio(1, 3)]);
+        exact("6 3 /", ints(&[2]));
+        exact("-1 3 /", vec![ratio(-1, 3)]);
+        exact("2 3 4 */", vec![ratio(3, 2)]);
+        exact("7 2 mod 7 2 /mod", ints(&[1, 1, 3]));
+        tcase_exact("1 0 /", Err(VmError::DivisionByZero("/".to_string())));
+        tcase_exact("1/2 0 /", Err(VmError::DivisionByZero("/".to_string())));
+        tcase_configured(|_| (), "1 0 ratio", Err(VmError::DivisionByZero("ratio".to_string())));
+    }
+
+    #[test]
+    fn test_ratio_conversions() {
+        tcase_source("7/2 >int -7/2 >int 2.9 >int -2.9 >int 5 >int", ints(&[3, -3, 2, -2, 5]));
+        tcase_source("1/4 >float 3 >float", floats(&[0.25, 3.0]));
+        tcase_source("6/4 numerator 6/4 denominator", ints(&[3, 2]));
+        tcase_source("5 numerator 5 denominator", ints(&[5, 1]));
+        tcase_configured(|_| (), "inf >int", Err(VmError::TypeMismatch {
+            word: ">int".to_string(),
+            expected: "finite number",
+            found: Prim::Float(f64::INFINITY),
+        }));
+        tcase_configured(|_| (), "0.5 numerator", Err(VmError::TypeMismatch {
+            word: "numerator".to_string(),
+            expected: "int or ratio",
+            found: Prim::Float(0.5),
+        }));
+        tcase_configured(|_| (), "1 0.5 ratio", Err(VmError::TypeMismatch {
+            word: "ratio".to_string(),
+            expected: "int or ratio",
+            found: Prim::Float(0.5),
+        }));
+        tcase_configured(|_| (), "1/2 1 and", Err(VmError::TypeMismatch {
+            word: "and".to_string(),
+            expected: "int or bool",
+            found: ratio(1, 2),
+        }));
+    }
+
+    #[test]
+    fn test_quotations() {
+        tcase_source("3 [ dup * ] call", ints(&[9]));
+        tcase_source("1 2 [ 10 + ] dip", ints(&[11, 2]));
+        tcase_source("5 [ 1+ ] keep", ints(&[6, 5]));
+        tcase_source("10 [ 1+ ] [ 1- ] bi", ints(&[11, 9]));
+        tcase_source("10 [ 1+ ] [ 1- ] [ 2* ] tri", ints(&[11, 9, 20]));
+        tcase_source("2 [ 3 + ] [ 4 * ] compose call", ints(&[20]));
+        tcase_source("3 4 [ + ] curry call", ints(&[7]));
+        tcase_source("[ [ 1 ] call 2 ] call", ints(&[1, 2]));
+        tcase_source("[ ] call [ ] [ ] compose call", ints(&[]));
+        tcase_source(": twice dup [ call ] dip call ; 1 [ 2* ] twice", ints(&[4]));
+        tcase_source("-3 [ dup 0< if negate then ] call", ints(&[3]));
+        tcase_source("[ 1 + ] [ 1 + ] =", ints(&[-1]));
+        tcase_source("[ 1 + ] dup drop", vec![Prim::Quot(vec![
+            Atom::lit(Prim::Int(1), Loc::default()),
+            Atom::symbol("+", Loc::default()),
+        ])]);
+    }
+
+    #[test]
+    fn test_quotation_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("1 2 call"),
+            VmError::TypeMismatch {
+                word: "call".to_string(),
+                expected: "quotation",
+                found: Prim::Int(2),
+            },
+            ints(&[1, 2]),
+        );
+        tcase_error(
+            source("[ 1 ] 2 [ 3 ] bi"),
+            VmError::TypeMismatch {
+                word: "bi".to_string(),
+                expected: "quotation",
+                found: Prim::Int(2),
+            },
+            vec![quot_of_int(1), Prim::Int(2), quot_of_int(3)],
+        );
+        tcase_error(
+            source("[ 1 ] dip"),
+            VmError::StackUnderflow {
+                word: "dip".to_string(),
+                needed: 2,
+                found: 1,
+            },
+            vec![quot_of_int(1)],
+        );
+        tcase_error(
+            source("[ 1 ] [ frobnicate ] compose call"),
+            VmError::UnknownWord("frobnicate".to_string()),
+            ints(&[1]),
+        );
+    }
+
+    fn quot_of_int(n: i64) -> Prim {
+        Prim::Quot(vec![Atom::lit(Prim::Int(n), Loc::default())])
+    }
+
+    #[test]
+    fn test_conditional_combinators() {
+        tcase_source("1 [ 10 ] [ 20 ] combinators:if 0 [ 10 ] [ 20 ] combinators:if", ints(&[10, 20]));
+        tcase_source("3 0< [ 1 ] [ 2 ] combinators:if", ints(&[2]));
+        tcase_source("true [ 1 ] when false [ 2 ] when", ints(&[1]));
+        tcase_source("true [ 1 ] unless false [ 2 ] unless", ints(&[2]));
+        // Any combinator can be called by its qualified name.
+        tcase_source("true [ 1 ] combinators:when [ 2 ] combinators:call", ints(&[1, 2]));
+        tcase_configured(|vm| vm.set_flag_style(FlagStyle::Bool),
+                         "1 2 < [ 3 ] [ 4 ] combinators:if", Ok(ints(&[3])));
+        tcase_source(": abs* dup 0< [ negate ] when ; -5 abs* 5 abs*", ints(&[5, 5]));
+    }
+
+    #[test]
+    fn test_looping_combinators() {
+        tcase_source("1 4 [ 2* ] times 5 0 [ 1+ ] times", ints(&[16, 5]));
+        tcase_source("0 [ dup 5 < ] [ 1+ ] combinators:while", ints(&[5]));
+        tcase_source("10 [ dup 5 < ] [ 1+ ] combinators:while", ints(&[10]));
+        tcase_source("0 [ 1+ dup 3 < ] combinators:loop", ints(&[3]));
+        tcase_source(": countdown [ dup 1- dup 0 > ] combinators:loop ; 3 countdown", ints(&[3, 2, 1, 0]));
+    }
+
+    #[test]
+    fn test_sequence_combinators() {
+        let quot = |values: &[i64]| Prim::Quot(
+            values.iter().map(|&n| Atom::lit(Prim::Int(n), Loc::default())).collect()
+        );
+        tcase_source("[ 1 2 3 ] [ 10 * ] each", ints(&[10, 20, 30]));
+        tcase_source("[ 1 2 3 ] [ dup * ] map", vec![quot(&[1, 4, 9])]);
+        tcase_source("[ ] [ dup * ] map", vec![quot(&[])]);
+        tcase_source("[ 1 2 3 4 ] [ 2 mod 0= ] filter", vec![quot(&[2, 4])]);
+        tcase_source("[ 1 2 3 4 ] 0 [ + ] reduce", ints(&[10]));
+        tcase_source("[ 1 2 3 ] 0 [ + ] reduce [ ] 7 [ + ] reduce", ints(&[6, 7]));
+        tcase_source("[ [ 1 ] [ 2 ] ] [ call ] each", ints(&[1, 2]));
+        tcase_source("[ 1 2 ] [ dup ] map", vec![quot(&[1, 1, 2, 2])]);
+    }
+
+    #[test]
+    fn test_cleave_spread() {
+        tcase_source("10 [ [ 1+ ] [ 1- ] [ 2* ] ] cleave", ints(&[11, 9, 20]));
+        tcase_source("10 [ 1+ 2* ] cleave", ints(&[11, 20]));
+        tcase_source("1 2 3 [ [ 10 + ] [ 20 + ] [ 30 + ] ] spread", ints(&[11, 22, 33]));
+        tcase_source("1 2 [ negate ] [ ] compose spread", ints(&[1, -2]));
+    }
+
+    #[test]
+    fn test_combinator_errors() {
+        let source = |text: &str| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(
+            source("\"x\" [ 1 ] [ 2 ] combinators:if"),
+            VmError::TypeMismatch {
+                word: "combinators:if".to_string(),
+                expected: "flag",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![Prim::Str("x".to_string()), quot_of_int(1), quot_of_int(2)],
+        );
+        tcase_error(
+            source("-1 [ 1 ] times"),
+            VmError::TypeMismatch {
+                word: "times".to_string(),
+                expected: "non-negative int",
+                found: Prim::Int(-1),
+            },
+            vec![Prim::Int(-1), quot_of_int(1)],
+        );
+        tcase_error(
+            source("5 [ 1 ] each"),
+            VmError::TypeMismatch {
+                word: "each".to_string(),
+                expected: "quotation",
+                found: Prim::Int(5),
+            },
+            vec![Prim::Int(5), quot_of_int(1)],
+        );
+        tcase_error(
+            source("1 [ 1 ] spread"),
+            VmError::TypeMismatch {
+                word: "spread".to_string(),
+                expected: "quotation",
+                found: Prim::Int(1),
+            },
+            vec![Prim::Int(1), quot_of_int(1)],
+        );
+        tcase_error(
+            source("1 [ 1+ 1- ] spread"),
+            VmError::StackUnderflow {
+                word: "spread".to_string(),
+                needed: 3,
+                found: 2,
+            },
+            vec![Prim::Int(1), Prim::Quot(vec![
+                Atom::symbol("1+", Loc::default()),
+                Atom::symbol("1-", Loc::default()),
+            ])],
+        );
+        tcase_error(
+            source("1 2 [ 5 ] [ drop drop ] map"),
+            VmError::StackUnderflow {
+                word: "map".to_string(),
+                needed: 2,
+                found: 1,
+            },
+            ints(&[1]),
+        );
+        tcase_error(
+            source("[ \"x\" ] combinators:loop"),
+            VmError::TypeMismatch {
+                word: "combinators:loop".to_string(),
+                expected: "flag",
+                found: Prim::Str("x".to_string()),
+            },
+            vec![
+                Prim::Str("x".to_string()),
+                Prim::Quot(vec![Atom::lit(Prim::Str("x".to_string()), Loc::default())]),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_prog_words() {
+        tcase_source(":: twice quote-next dup [ call ] dip call ; 1 twice 2*", ints(&[4]));
+        tcase_source(":: unless-zero dup 0 = [ skip ] when ; 0 unless-zero 1+ 5 unless-zero 1+",
+                     ints(&[0, 6]));
+        tcase_source(":: later [ 10 ] inject 1 ; later 2", ints(&[1, 10, 2]));
+        tcase_source("peek-next 5 drop", vec![quot_of_int(5)]);
+        // Ordinary words see through to the program word that called them...
+        tcase_source(": helper skip ; :: skip-next helper ; 1 skip-next 2 3", ints(&[1, 3]));
+        // ... but on their own, they see the rest of their own body.
+        tcase_source(": f skip 1 2 ; f skip 3", ints(&[2]));
+    }
+
+    #[test]
+    fn test_prog_word_errors() {
+        let source = |text| sltf_parse::Parser::new().parse(text).unwrap();
+        tcase_error(source("1 skip"), VmError::NothingNext("skip".to_string()), ints(&[1]));
+        tcase_error(
+            source(":: f quote-next ; : g f ; g 1"),
+            VmError::NothingNext("quote-next".to_string()),
+            vec![],
+        );
+        // The values the vm keeps on the program stack for itself aren't
+        // part of the program.
+        tcase_error(
+            source("[ \"a\" ] [ skip ] map ."),
+            VmError::NothingNext("skip".to_string()),
+            vec![Prim::Str("a".to_string())],
+        );
+        tcase_error(source("1 [ skip ] dip"), VmError::NothingNext("skip".to_string()), vec![]);
+        tcase_error(
+            source("1 inject"),
+            VmError::TypeMismatch {
+                word: "inject".to_string(),
+                expected: "quotation",
+                found: Prim::Int(1),
+            },
+            ints(&[1]),
+        );
+    }
+
+    // An output sink whose contents a test can read back.
+    #[derive(Clone,Default)]
+    struct SharedOutput(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output() {
+        let output = SharedOutput::default();
+        let sink = output.clone();
+        tcase_configured(
+            move |vm| vm.set_output(Box::new(sink.clone())),
+            "1 2/3 [ dup * ] . 2drop 1.5 f. : sq dup * ; : sq dup * ;",
+            Ok(ints(&[1])),
+        );
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            " 1 2/3 [ dup * ] 1.5Defined new word \"sq\"\nRedefined word \"sq\"\n",
+        );
+    }
+
+    #[test]
+    fn test_host_builtins() {
+        let count = Rc::new(std::cell::Cell::new(0));
+        let counter = count.clone();
+        tcase_configured(
+            move |vm| {
+                let counter = counter.clone();
+                vm.define_builtin("count", move |vm| {
+                    counter.set(counter.get() + 1);
+                    vm.stack.push(Prim::Int(counter.get()));
+                    Ok(())
+                });
+            },
+            "count count [ count ] 2 swap times",
+            Ok(ints(&[1, 2, 3, 4])),
+        );
+        assert_eq!(count.get(), 4);
+        // Host words can replace the vm's own, and report errors like them.
+        let refuse = |vm: &mut Vm| vm.define_builtin("dup", |_| Err(VmError::UnknownWord("dup".to_string())));
+        tcase_configured(refuse, "1 dup", Err(VmError::UnknownWord("dup".to_string())));
+    }
+}
@@ -0,0 +1,57 @@
+// Words that work on the program stack rather than the data stack. They're
+// meant for program words (defined with `::`), where they see the program
+// that follows the program word's call; that's how a program word can
+// build its own control structure, e.g.
+//
+//     :: twice quote-next dup [ call ] dip call ;
+//     1 twice 2*
+//
+// Outside of a program word, they see whatever directly follows them.
+// Either way, they can't see past anything the vm has put on the program
+// stack for itself (e.g. the end of a loop body, or the value that `dip`
+// restores).
+
+use std::rc::Rc;
+
+use either::Left;
+
+use super::combinators::{pop_quot, require_quots};
+use super::{Prim, Vm, VmError, WordMap};
+
+pub fn add_words(word_map: &mut WordMap) {
+    word_map.insert("skip".to_string(), Left(Rc::new(bi_skip)));
+    word_map.insert("peek-next".to_string(), Left(Rc::new(bi_peek_next)));
+    word_map.insert("quote-next".to_string(), Left(Rc::new(bi_quote_next)));
+    word_map.insert("inject".to_string(), Left(Rc::new(bi_inject)));
+}
+
+// ( -- ) Drop the next atom of the program without running it.
+fn bi_skip(vm: &mut Vm) -> Result<(), VmError> {
+    vm.take_next_atom("skip")?;
+    Ok(())
+}
+
+// ( -- quot ) Push the next atom of the program as a quotation, leaving
+// it in the program.
+fn bi_peek_next(vm: &mut Vm) -> Result<(), VmError> {
+    let atom = vm.next_atom("peek-next")?.clone();
+    vm.stack.push(Prim::Quot(vec![atom]));
+    Ok(())
+}
+
+// ( -- quot ) Take the next atom out of the program, and push it as a
+// quotation.
+fn bi_quote_next(vm: &mut Vm) -> Result<(), VmError> {
+    let atom = vm.take_next_atom("quote-next")?;
+    vm.stack.push(Prim::Quot(vec![atom]));
+    Ok(())
+}
+
+// ( quot -- ) Put the quotation's body into the program, to run once the
+// program word is finished.
+fn bi_inject(vm: &mut Vm) -> Result<(), VmError> {
+    require_quots(&vm.stack, "inject", 1, 0)?;
+    let body = pop_quot(&mut vm.stack);
+    vm.inject(&body);
+    Ok(())
+}
@@ -1,21 +1,62 @@
+use std::env;
 use std::io;
+use std::process;
 
 extern crate sltf_parse;
 extern crate sltf_vm;
 
-use sltf_parse::{Prim,Parser};
-use sltf_vm::{Vm};
+use sltf_parse::{Parser, ParseError};
+use sltf_vm::{FlagStyle, IntDivision, OverflowPolicy, Vm, VmError};
 
 
+// Pass `--rollback` to restore the data stack after a line fails,
+// `--wrap`, `--saturate` or `--promote` to change what integer overflow
+// does (by default it's an error), `--exact` to have `/` give a ratio rather
+// than a floored int, and `--bool-flags` to have comparisons push bools
+// rather than Forth-style -1/0 flags.
 fn main() {
     use io::BufRead;
-    let mut runner = Runner::new("");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let has_flag = |flag: &str| args.iter().any(|arg| arg == flag);
+    let mut runner = Runner::new(has_flag("--rollback"));
+    if has_flag("--wrap") {
+        runner.vm.set_overflow_policy(OverflowPolicy::Wrap);
+    } else if has_flag("--saturate") {
+        runner.vm.set_overflow_policy(OverflowPolicy::Saturate);
+    } else if has_flag("--promote") {
+        runner.vm.set_overflow_policy(OverflowPolicy::Promote);
+    }
+    if has_flag("--exact") {
+        runner.vm.set_int_division(IntDivision::Exact);
+    }
+    if has_flag("--bool-flags") {
+        runner.vm.set_flag_style(FlagStyle::Bool);
+    }
     let stdin = io::stdin();
     println!(" ok");
     for line in stdin.lock().lines() {
-        runner.parse_input(line.unwrap().as_ref());
-        runner.run();
-        println!(" ok");
+        let line = match line {
+            Ok(line) => line,
+            // The line has been read past, so we can carry on without it.
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("skipped a line that isn't valid UTF-8");
+                continue;
+            },
+            Err(err) => {
+                eprintln!("failed to read input: {}", err);
+                process::exit(1);
+            },
+        };
+        if let Err(message) = runner.interpret(&line) {
+            println!("{}", message);
+        }
+        if runner.parser.is_pending() {
+            // The line ended inside a definition, string or comment; wait
+            // for more.
+            println!(" ...");
+        } else {
+            println!(" ok");
+        }
     }
 }
 
@@ -23,44 +64,90 @@ fn main() {
 struct Runner {
     parser: Parser,
     vm: Vm,
+    // Whether a failed line should leave the data stack as it was before
+    // the line began, rather than as it was when the error happened.
+    rollback: bool,
 }
 
 impl Runner {
 
-    fn new(initial_input: &str) -> Self {
-        let parser = Parser::new();
-        let prog = parser.parse(initial_input);
-        let vm = Vm::new(prog);
+    fn new(rollback: bool) -> Self {
         Runner {
-            parser,
-            vm,
+            parser: Parser::new(),
+            vm: Vm::new(Vec::new()),
+            rollback,
+        }
+    }
+
+    // Parse and run one line of input. On failure, the rest of the line
+    // is thrown away (but any words it already defined are kept) and we
+    // return a message describing what went wrong.
+    fn interpret(&mut self, line: &str) -> Result<(), String> {
+        if let Err(errors) = self.parse_input(line) {
+            let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+            return Err(messages.join("\n"));
         }
+        let saved_stack = if self.rollback { Some(self.vm.stack.clone()) } else { None };
+        self.run().map_err(|err| {
+            self.vm.clear_prog();
+            if let Some(stack) = saved_stack {
+                self.vm.stack = stack;
+            }
+            format!(" {} ?", diagnostic(&err))
+        })
     }
 
-    fn parse_input(&mut self, input: &str) {
-        let prog = self.parser.parse(input);
+    fn parse_input(&mut self, input: &str) -> Result<(), Vec<ParseError>> {
+        let prog = self.parser.feed(input)?;
         self.vm.new_input(prog);
+        Ok(())
     }
 
-    fn execute(&mut self) {
+    fn execute(&mut self) -> Result<(), VmError> {
         self.vm.execute()
     }
 
-    fn run(&mut self) {
+    fn run(&mut self) -> Result<(), VmError> {
         while !self.vm.finished() {
-            self.execute()
+            self.execute()?
         }
+        Ok(())
     }
 }
-    
 
+
+// A short, Forth-style description of a runtime error. Errors raised inside
+// a word definition are blamed on that definition, since that's the word
+// the user actually typed.
+fn diagnostic(err: &VmError) -> String {
+    let definition = match err {
+        VmError::At(loc, _) => loc.word.as_ref().map(|word| word.to_string()),
+        _ => None,
+    };
+    let context = |word: &str| definition.clone().unwrap_or_else(|| word.to_string());
+    match err.root() {
+        VmError::StackUnderflow { word, .. } =>
+            format!("stack underflow in {:?}", context(word)),
+        VmError::ReturnStackUnderflow { word, .. } =>
+            format!("return stack underflow in {:?}", context(word)),
+        VmError::TypeMismatch { word, expected, .. } =>
+            format!("type mismatch in {:?}, expected {}", context(word), expected),
+        other =>
+            other.to_string(),
+    }
+}
+
+
+#[cfg(test)]
 mod runner_tests {
     use super::*;
+    use sltf_parse::Prim;
 
     fn tcase_from_init(init: &str,
                        expected_stack: Vec<Prim>) {
-        let mut runner = Runner::new(init);
-        runner.run();
+        let mut runner = Runner::new(false);
+        runner.parse_input(init).unwrap();
+        runner.run().unwrap();
         assert_eq!(runner.vm.stack,  expected_stack);
     }
 
@@ -86,4 +173,56 @@ mod runner_tests {
         );
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_runner_recovers_from_error() {
+        let mut runner = Runner::new(false);
+        assert_eq!(
+            runner.interpret(": square dup * ; 1 2 drop drop square 7"),
+            Err(" stack underflow in \"square\" ?".to_string())
+        );
+        assert!(runner.vm.finished());
+        assert_eq!(runner.vm.stack, vec![]);
+        assert_eq!(runner.interpret("3 square"), Ok(()));
+        assert_eq!(runner.vm.stack, vec![Prim::Int(9)]);
+    }
+
+    #[test]
+    fn test_runner_rollback() {
+        let mut runner = Runner::new(true);
+        runner.interpret("1 2").unwrap();
+        assert_eq!(
+            runner.interpret("+ + 5"),
+            Err(" stack underflow in \"+\" ?".to_string())
+        );
+        assert_eq!(runner.vm.stack, vec![Prim::Int(1), Prim::Int(2)]);
+    }
+
+    #[test]
+    fn test_runner_without_rollback() {
+        let mut runner = Runner::new(false);
+        runner.interpret("1 2").unwrap();
+        assert!(runner.interpret("+ + 5").is_err());
+        assert_eq!(runner.vm.stack, vec![Prim::Int(3)]);
+    }
+
+    #[test]
+    fn test_runner_parse_error() {
+        let mut runner = Runner::new(false);
+        let message = runner.interpret("1 ; 2").unwrap_err();
+        assert!(message.starts_with("line 1, column 3:"));
+        assert_eq!(runner.vm.stack, vec![]);
+    }
+
+    #[test]
+    fn test_runner_multiline_definition() {
+        let mut runner = Runner::new(false);
+        runner.interpret("3 : square").unwrap();
+        assert!(runner.parser.is_pending());
+        assert_eq!(runner.vm.stack, vec![Prim::Int(3)]);
+        runner.interpret("dup *").unwrap();
+        assert!(runner.parser.is_pending());
+        runner.interpret("; square").unwrap();
+        assert!(!runner.parser.is_pending());
+        assert_eq!(runner.vm.stack, vec![Prim::Int(9)]);
+    }
+}